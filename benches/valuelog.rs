@@ -8,12 +8,12 @@ extern crate test;
 use test::Bencher;
 use spiderdb::values::{ValueLog, ValueOption};
 use spiderdb::values::Value;
-use rand::{Rand, Rng, StdRng};
-use std::path;
+use spiderdb::table::TableLoadMode;
+use rand::Rng;
 #[bench]
 fn bench_value_write_sync(b: &mut Bencher) {
     let tmp_dir = tempdir::TempDir::new("value_log").unwrap().into_path();
-    let mut vl = ValueLog::open(&ValueOption::new(&tmp_dir, 1024 * 1024 * 96, true)).unwrap();
+    let vl = ValueLog::open(&ValueOption::new(&tmp_dir, 1024 * 1024 * 96, true, TableLoadMode::MemoryMap)).unwrap();
     let mut rng = rand::thread_rng();
 
     b.iter(|| {
@@ -29,7 +29,7 @@ fn bench_value_write_sync(b: &mut Bencher) {
 #[bench]
 fn bench_value_write_nosync(b: &mut Bencher) {
     let tmp_dir = tempdir::TempDir::new("value_log").unwrap().into_path();
-    let mut vl = ValueLog::open(&ValueOption::new(&tmp_dir, 1024 * 1024 * 96, false)).unwrap();
+    let vl = ValueLog::open(&ValueOption::new(&tmp_dir, 1024 * 1024 * 96, false, TableLoadMode::MemoryMap)).unwrap();
     let mut rng = rand::thread_rng();
 
     b.iter(|| {
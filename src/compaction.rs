@@ -0,0 +1,535 @@
+use failure::Error;
+use iterator::{MergeIterator, SeekIterator, TableValueIterator};
+use keys::{parse_key, parse_ts, KeyRange};
+use level::{can_drop_tombstone, LevelHandler};
+use manifest::ManifestChange;
+use std::collections::Bound;
+use std::fs;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::Arc;
+use table::builder::{TableBuilder, DEFAULT_BLOCK_SIZE};
+use table::{table_path, Table};
+use values::ValueStruct;
+use {Core, DB};
+
+/// A run of tables joins a tiered compaction if it's at most this times
+/// the total size of the newer runs in the compaction.
+const TIERED_SIZE_RATIO: f64 = 1.2;
+
+/// How tables are compacted, set by `ConfigBuilder::compaction_style`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CompactionStyle {
+    /// Each level is a sorted run about `LEVEL_SIZE_MULTIPLIER` times bigger than the one above,
+    /// a table is merged with the overlapping tables in the next level when its level is full.
+    Leveled,
+    /// Each level is a sorted run, and runs of similar sizes are merged together,
+    /// which writes less than `Leveled` but leaves more runs to read.
+    Tiered,
+}
+
+// Tables merged into `target`, grouped by levels from the newest to the oldest.
+pub(crate) struct CompactDef {
+    inputs: Vec<(usize, Vec<Arc<Table>>)>,
+    target: usize,
+    // whether a single table can be moved to `target` without being rewritten.
+    allow_move: bool,
+}
+
+impl CompactDef {
+    // Merge `top` tables of `level` with the overlapping tables in the next level.
+    fn leveled(levels: &[LevelHandler], level: usize, top: Vec<Arc<Table>>) -> Option<CompactDef> {
+        let smallest = top.iter().map(|t| parse_key(t.smallest())).min()?;
+        let biggest = top.iter().map(|t| parse_key(t.biggest())).max()?;
+        let bottom = levels[level + 1].overlapping(smallest, biggest);
+        Some(CompactDef {
+            inputs: vec![(level, top), (level + 1, bottom)],
+            target: level + 1,
+            allow_move: true,
+        })
+    }
+
+    fn tables(&self) -> impl Iterator<Item = &Arc<Table>> {
+        self.inputs.iter().flat_map(|(_, tables)| tables.iter())
+    }
+
+    fn changes(&self, added: &[Arc<Table>]) -> Vec<ManifestChange> {
+        let mut changes = vec![];
+        for (level, tables) in self.inputs.iter() {
+            for t in tables.iter() {
+                changes.push(ManifestChange::DeleteTable {
+                    level: *level as u32,
+                    id: t.id(),
+                });
+            }
+        }
+        for t in added.iter() {
+            changes.push(ManifestChange::AddTable {
+                level: self.target as u32,
+                id: t.id(),
+                smallest: t.smallest().to_vec(),
+                biggest: t.biggest().to_vec(),
+            });
+        }
+        changes
+    }
+}
+
+// A strategy picking the next compaction from levels.
+pub(crate) trait CompactionPicker: Send + Sync {
+    fn pick(&self, levels: &[LevelHandler]) -> Option<CompactDef>;
+}
+
+pub(crate) fn new_picker(style: CompactionStyle) -> Box<dyn CompactionPicker> {
+    match style {
+        CompactionStyle::Leveled => Box::new(LeveledPicker),
+        CompactionStyle::Tiered => Box::new(TieredPicker),
+    }
+}
+
+struct LeveledPicker;
+
+impl CompactionPicker for LeveledPicker {
+    // Pick the level with the highest score, the last level is never compacted.
+    // All tables of level 0 are compacted together since they overlap with each other,
+    // in other levels the oldest table is picked.
+    fn pick(&self, levels: &[LevelHandler]) -> Option<CompactDef> {
+        let (level, score) = levels[..levels.len() - 1]
+            .iter()
+            .map(|l| (l.level() as usize, l.score()))
+            .fold((0, 0.0), |max, s| if s.1 > max.1 { s } else { max });
+        if score < 1.0 {
+            return None;
+        }
+        let top: Vec<Arc<Table>> = if level == 0 {
+            levels[0].tables().to_vec()
+        } else {
+            let t = levels[level].tables().iter().min_by_key(|t| t.id())?;
+            vec![Arc::clone(t)]
+        };
+        CompactDef::leveled(levels, level, top)
+    }
+}
+
+struct TieredPicker;
+
+impl CompactionPicker for TieredPicker {
+    // Each non-empty level below level 0 is a sorted run, and older runs are in lower levels.
+    // Once level 0 is full, its tables are merged with the following runs of similar sizes,
+    // and the result is written right above the next older run, or into the last level.
+    // The run in level 1 is always merged, since there is no room above it.
+    fn pick(&self, levels: &[LevelHandler]) -> Option<CompactDef> {
+        if levels[0].score() < 1.0 {
+            return None;
+        }
+        let mut inputs = vec![(0, levels[0].tables().to_vec())];
+        let mut size = levels[0].size() as f64;
+        let mut target = levels.len() - 1;
+        for l in levels[1..].iter().filter(|l| !l.tables().is_empty()) {
+            let level = l.level() as usize;
+            if level > 1 && l.size() as f64 > size * TIERED_SIZE_RATIO {
+                target = level - 1;
+                break;
+            }
+            inputs.push((level, l.tables().to_vec()));
+            size += l.size() as f64;
+        }
+        Some(CompactDef {
+            inputs,
+            target,
+            allow_move: true,
+        })
+    }
+}
+
+// Impl compaction
+impl Core {
+    // Compact levels until no level needs it, jobs run one by one,
+    // so tables below the levels being compacted never change during a compaction.
+    pub(crate) fn schedule_compaction(self: &Arc<Self>) {
+        let core = Arc::clone(self);
+        self.compact_pool.execute(move || loop {
+            let def = match core.picker.pick(&core.levels.read().unwrap()) {
+                Some(def) => def,
+                None => return,
+            };
+            if let Err(e) = core.run_compaction(def) {
+                core.set_bg_err(e);
+                return;
+            }
+            core.bg_cond.notify_all();
+        });
+    }
+
+    fn run_compaction(&self, def: CompactDef) -> Result<(), Error> {
+        // a table overlapping nothing below is moved without being rewritten.
+        let tables: Vec<Arc<Table>> = def.tables().cloned().collect();
+        if def.allow_move && tables.len() == 1 {
+            return self.install_compaction(&def, tables);
+        }
+
+        // newer sources first, tables in level 0 are appended from old to new.
+        let mut sources: Vec<Box<dyn SeekIterator>> = vec![];
+        for (_, tables) in def.inputs.iter() {
+            for t in tables.iter().rev() {
+                sources.push(Box::new(TableValueIterator::new(t.iter())));
+            }
+        }
+        let mut it = MergeIterator::new(sources);
+        let levels: Vec<LevelHandler> = self.levels.read().unwrap().clone();
+        let discard_ts = self.orc.discard_ts();
+
+        let mut added = vec![];
+        let mut discarded = vec![];
+        let mut builder = self.new_builder();
+        let mut last_key: Option<Vec<u8>> = None;
+        // whether a version visible to all transactions is already kept for `last_key`.
+        let mut skip_older = false;
+        for (key, vs) in &mut it {
+            let is_new_key = last_key.as_ref().map(|k| &k[..]) != Some(parse_key(&key));
+            if is_new_key {
+                last_key = Some(parse_key(&key).to_vec());
+                skip_older = false;
+            } else if skip_older {
+                // counted as discarded when it was overwritten.
+                continue;
+            }
+            if parse_ts(&key) <= discard_ts {
+                skip_older = true;
+                if vs.is_deleted() && can_drop_tombstone(&levels, def.target, parse_key(&key)) {
+                    if is_new_key {
+                        discarded.push(vs.pointer);
+                    }
+                    continue;
+                }
+            }
+            // versions of a key are kept in the same table.
+            if is_new_key && builder.estimated_size() >= self.cfg.max_table_size as usize {
+                let full = ::std::mem::replace(&mut builder, self.new_builder());
+                added.push(self.create_table(full)?);
+            }
+            let mut value = Vec::with_capacity(ValueStruct::SIZE as usize);
+            vs.encode(&mut value)?;
+            builder.add(&key, &value);
+        }
+        if let Some(e) = it.err() {
+            return Err(format_err!("compaction into level {}: {}", def.target, e));
+        }
+        if !builder.is_empty() {
+            added.push(self.create_table(builder)?);
+        }
+
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.compactions += 1;
+            stats.compacted_bytes += added.iter().map(|t| t.size()).sum::<u64>();
+        }
+        self.install_compaction(&def, added)?;
+        self.vlog.read().unwrap().update_discard_stats(&discarded);
+        for t in tables.iter() {
+            fs::remove_file(table_path(&self.cfg.dir, t.id()))?;
+        }
+        Ok(())
+    }
+
+    // Swap the input tables of `def` with `added` in manifest first, then in levels.
+    fn install_compaction(&self, def: &CompactDef, added: Vec<Arc<Table>>) -> Result<(), Error> {
+        let mut changes = def.changes(&added);
+        changes.push(ManifestChange::SetNextFileId(
+            self.next_table_id.load(Ordering::SeqCst),
+        ));
+        self.manifest.lock().unwrap().add_changes(&changes)?;
+
+        let mut levels = self.levels.write().unwrap();
+        let mut removed = vec![];
+        for (level, tables) in def.inputs.iter() {
+            let ids: Vec<u64> = tables.iter().map(|t| t.id()).collect();
+            if *level == def.target {
+                removed = ids;
+            } else {
+                levels[*level].replace_tables(&ids, vec![]);
+            }
+        }
+        levels[def.target].replace_tables(&removed, added);
+        Ok(())
+    }
+
+    fn new_builder(&self) -> TableBuilder {
+        TableBuilder::new(DEFAULT_BLOCK_SIZE, self.cfg.bloom_bits_per_key)
+    }
+
+    // Push tables overlapping `range` down level by level,
+    // until they are all in the deepest level holding any of them, or level 1 at least.
+    // Tables are always rewritten, so that tombstones and obsolete versions are dropped.
+    fn compact_range(&self, range: &KeyRange) -> Result<(), Error> {
+        let overlapping = |l: &LevelHandler| -> Vec<Arc<Table>> {
+            l.tables()
+                .iter()
+                .filter(|t| range.overlaps(parse_key(t.smallest()), parse_key(t.biggest())))
+                .cloned()
+                .collect()
+        };
+        let levels = self.levels.read().unwrap();
+        let bottom = match levels.iter().rposition(|l| !overlapping(l).is_empty()) {
+            Some(level) => level.max(1),
+            None => return Ok(()),
+        };
+        drop(levels);
+        for level in 0..bottom {
+            let def = {
+                let levels = self.levels.read().unwrap();
+                // tables in level 0 may overlap with each other, they are moved together.
+                let top = if level == 0 {
+                    levels[0].tables().to_vec()
+                } else {
+                    overlapping(&levels[level])
+                };
+                CompactDef::leveled(&levels, level, top)
+            };
+            if let Some(mut def) = def {
+                def.allow_move = false;
+                self.run_compaction(def)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Impl manual compaction
+impl DB {
+    /// Compact tables holding keys in `[start, end]`, `None` means unbounded.
+    /// The memtable is flushed first, then the tables are pushed down until the range is in one level,
+    /// it blocks until the compaction is done.
+    pub fn compact_range(&self, start: Option<&[u8]>, end: Option<&[u8]>) -> Result<(), Error> {
+        {
+            let mut lsm = self.core.lsm.write().unwrap();
+            if !lsm.is_mt_empty() {
+                lsm.freeze();
+                self.core.schedule_flush();
+            }
+        }
+        self.core.flush_pool.join();
+        if let Some(e) = self.core.bg_err.lock().unwrap().take() {
+            return Err(e);
+        }
+
+        let range = KeyRange::new::<&[u8], _>((bound(start), bound(end)));
+        let (tx, rx) = mpsc::channel();
+        let core = Arc::clone(&self.core);
+        // run along with other compactions one by one.
+        self.core.compact_pool.execute(move || {
+            let res = core.compact_range(&range);
+            if res.is_ok() {
+                core.schedule_compaction();
+            }
+            // the caller is waiting for it.
+            let _ = tx.send(res);
+        });
+        rx.recv()?
+    }
+
+    /// Compact all tables into one level, e.g. before taking a backup.
+    pub fn flatten(&self) -> Result<(), Error> {
+        self.compact_range(None, None)
+    }
+}
+
+fn bound(key: Option<&[u8]>) -> Bound<&[u8]> {
+    match key {
+        Some(key) => Bound::Included(key),
+        None => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use table::parse_table_id;
+    use table::tests::build_table_with_id;
+    use {ConfigBuilder, DB};
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    fn wait_background(db: &DB) {
+        db.core.flush_pool.join();
+        db.core.compact_pool.join();
+        assert!(db.core.bg_err.lock().unwrap().is_none());
+    }
+
+    // Tables in levels are exactly the table files.
+    fn check_table_files(db: &DB) {
+        let mut ids = HashSet::new();
+        for level in db.core.levels.read().unwrap().iter() {
+            for t in level.tables() {
+                assert!(ids.insert(t.id()));
+            }
+        }
+        let files: HashSet<u64> = fs::read_dir(db.core.cfg.dir())
+            .unwrap()
+            .filter_map(|e| e.unwrap().file_name().to_str().and_then(parse_table_id))
+            .collect();
+        assert_eq!(ids, files);
+    }
+
+    fn check_compaction(style: CompactionStyle) {
+        let tmp_dir = ::tempdir::TempDir::new("db").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path().join("lsm"))
+            .value_dir(tmp_dir.path().join("vlog"))
+            .max_table_size(1024)
+            .compaction_style(style)
+            .build();
+        let value = |i: usize, round: usize| format!("value{}-{}", i, round).into_bytes();
+        {
+            let db = DB::open(&cfg).unwrap();
+            db.set(&key(0), &value(0, 0)).unwrap();
+            let mut txn = db.begin(true);
+            for round in 0..4 {
+                for i in 0..300 {
+                    db.set(&key(i), &value(i, round)).unwrap();
+                }
+            }
+            for i in (0..300).filter(|i| i % 3 == 0) {
+                db.delete(&key(i)).unwrap();
+            }
+            wait_background(&db);
+
+            let stats = db.stats();
+            assert!(stats.compactions > 0);
+            assert!(stats.write_amplification() > 1.0);
+            let levels = db.core.levels.read().unwrap();
+            assert!(levels[0].score() < 1.0);
+            assert!(levels[1..].iter().any(|l| !l.tables().is_empty()));
+            for level in levels[1..].iter() {
+                // tables in a level don't overlap.
+                for pair in level.tables().windows(2) {
+                    assert!(parse_key(pair[0].biggest()) < parse_key(pair[1].smallest()));
+                }
+            }
+            drop(levels);
+            check_table_files(&db);
+
+            // the version read by a pending transaction is kept.
+            assert_eq!(Some(value(0, 0)), txn.get(&key(0)).unwrap());
+            txn.discard();
+            for i in 0..300 {
+                let expected = if i % 3 == 0 { None } else { Some(value(i, 3)) };
+                assert_eq!(expected, db.get(&key(i)).unwrap());
+            }
+        }
+
+        // compacted tables are recorded in manifest.
+        let db = DB::open(&cfg).unwrap();
+        wait_background(&db);
+        check_table_files(&db);
+        for i in 0..300 {
+            let expected = if i % 3 == 0 { None } else { Some(value(i, 3)) };
+            assert_eq!(expected, db.get(&key(i)).unwrap());
+        }
+        let mut it = db.iter();
+        let mut count = 0;
+        while it.valid() {
+            count += 1;
+            it.next();
+        }
+        assert_eq!(200, count);
+    }
+
+    #[test]
+    fn test_leveled_compaction() {
+        check_compaction(CompactionStyle::Leveled);
+    }
+
+    #[test]
+    fn test_tiered_compaction() {
+        check_compaction(CompactionStyle::Tiered);
+    }
+
+    #[test]
+    fn test_tiered_picker() {
+        let mut next_id = 0;
+        let mut run = |size: usize| {
+            next_id += 1;
+            let kvs = (0..size)
+                .map(|i| (::keys::key_with_ts(&key(i), 1), vec![0; 64]))
+                .collect();
+            build_table_with_id(next_id, &[kvs])
+        };
+        let mut levels: Vec<LevelHandler> = (0..5).map(|l| LevelHandler::new(l, 1 << 20)).collect();
+        for _ in 0..::level::L0_COMPACTION_TRIGGER - 1 {
+            levels[0].add_table(run(10));
+        }
+        assert!(TieredPicker.pick(&levels).is_none());
+        levels[0].add_table(run(10));
+        let inputs = |def: &CompactDef| -> Vec<usize> {
+            def.inputs.iter().map(|(level, _)| *level).collect()
+        };
+
+        // nothing below, the result goes to the last level.
+        let def = TieredPicker.pick(&levels).unwrap();
+        assert_eq!((vec![0], 4), (inputs(&def), def.target));
+
+        // a much bigger run is left alone.
+        levels[4].add_table(run(200));
+        let def = TieredPicker.pick(&levels).unwrap();
+        assert_eq!((vec![0], 3), (inputs(&def), def.target));
+
+        // runs of similar sizes are merged.
+        levels[2].add_table(run(40));
+        levels[3].add_table(run(80));
+        let def = TieredPicker.pick(&levels).unwrap();
+        assert_eq!((vec![0, 2, 3], 3), (inputs(&def), def.target));
+
+        // the run in level 1 leaves no room above it.
+        levels[1].add_table(run(1000));
+        let def = TieredPicker.pick(&levels).unwrap();
+        assert_eq!((vec![0, 1, 2, 3, 4], 4), (inputs(&def), def.target));
+    }
+
+    #[test]
+    fn test_compact_range() {
+        let tmp_dir = ::tempdir::TempDir::new("db").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path().join("lsm"))
+            .value_dir(tmp_dir.path().join("vlog"))
+            .max_table_size(4096)
+            .build();
+        let db = DB::open(&cfg).unwrap();
+        for i in 0..300 {
+            db.set(&key(i), &key(i)).unwrap();
+        }
+        db.flatten().unwrap();
+        let table_levels = || -> Vec<usize> {
+            let levels = db.core.levels.read().unwrap();
+            (0..levels.len())
+                .filter(|&l| !levels[l].tables().is_empty())
+                .collect()
+        };
+        assert!(db.core.lsm.read().unwrap().is_mt_empty());
+        assert_eq!(vec![1], table_levels());
+        check_table_files(&db);
+
+        // tombstones and deleted values are dropped from tables.
+        for i in 100..200 {
+            db.delete(&key(i)).unwrap();
+        }
+        db.compact_range(Some(&key(100)), Some(&key(199))).unwrap();
+        assert_eq!(vec![1], table_levels());
+        for t in db.core.levels.read().unwrap()[1].tables() {
+            for (k, _) in t.iter() {
+                let i: usize = String::from_utf8_lossy(&parse_key(&k)[3..]).parse().unwrap();
+                assert!(!(100..200).contains(&i), "{}", i);
+            }
+        }
+        for i in 0..300 {
+            let expected = if (100..200).contains(&i) { None } else { Some(key(i)) };
+            assert_eq!(expected, db.get(&key(i)).unwrap());
+        }
+
+        // nothing to compact.
+        let stats = db.stats();
+        db.compact_range(Some(b"x"), None).unwrap();
+        assert_eq!(stats, db.stats());
+    }
+}
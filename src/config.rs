@@ -0,0 +1,299 @@
+// the impls generated by `derive(Fail)` are non-local.
+#![allow(non_local_definitions)]
+
+use compaction::CompactionStyle;
+use level::L0_COMPACTION_TRIGGER;
+use std::path::{Path, PathBuf};
+use table::bloom::DEFAULT_BITS_PER_KEY;
+use table::TableLoadMode;
+use values::DEFAULT_SEGMENT_MAX_SIZE;
+
+#[derive(Debug, Fail)]
+pub enum ConfigError {
+    #[fail(display = "max_table_size({}) should be in range (0, {}]", size, max)]
+    InvalidMaxTableSize { size: u64, max: u64 },
+    #[fail(display = "value_log_file_size should be greater than 0")]
+    InvalidValueLogFileSize,
+    #[fail(display = "value dir({:?}) should not be nested inside dir({:?})", value_dir, dir)]
+    NestedValueDir { dir: PathBuf, value_dir: PathBuf },
+    #[fail(
+        display = "level0_slowdown_tables({}) should not exceed level0_stop_tables({}), which should be greater than {}",
+        slowdown, stop, min
+    )]
+    InvalidLevel0Stall {
+        slowdown: usize,
+        stop: usize,
+        min: usize,
+    },
+    #[fail(display = "max_imm_memtables should be greater than 0")]
+    InvalidMaxImmMemtables,
+}
+
+/// Options to open a `DB`, use `ConfigBuilder` to create one.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub(crate) dir: PathBuf,
+    pub(crate) value_dir: PathBuf,
+    pub(crate) sync_write: bool,
+    pub(crate) table_loading_mode: TableLoadMode,
+    pub(crate) value_log_loading_mode: TableLoadMode,
+    pub(crate) max_table_size: u64,
+    pub(crate) value_log_file_size: u32,
+    pub(crate) bloom_bits_per_key: usize,
+    pub(crate) compaction_style: CompactionStyle,
+    pub(crate) level0_slowdown_tables: usize,
+    pub(crate) level0_stop_tables: usize,
+    pub(crate) max_imm_memtables: usize,
+    pub(crate) block_cache_size: usize,
+}
+
+impl Config {
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    pub fn value_dir(&self) -> &Path {
+        &self.value_dir
+    }
+    pub fn sync_write(&self) -> bool {
+        self.sync_write
+    }
+    pub fn table_loading_mode(&self) -> TableLoadMode {
+        self.table_loading_mode
+    }
+    pub fn value_log_loading_mode(&self) -> TableLoadMode {
+        self.value_log_loading_mode
+    }
+    pub fn max_table_size(&self) -> u64 {
+        self.max_table_size
+    }
+    pub fn value_log_file_size(&self) -> u32 {
+        self.value_log_file_size
+    }
+    pub fn bloom_bits_per_key(&self) -> usize {
+        self.bloom_bits_per_key
+    }
+    pub fn compaction_style(&self) -> CompactionStyle {
+        self.compaction_style
+    }
+    pub fn level0_slowdown_tables(&self) -> usize {
+        self.level0_slowdown_tables
+    }
+    pub fn level0_stop_tables(&self) -> usize {
+        self.level0_stop_tables
+    }
+    pub fn max_imm_memtables(&self) -> usize {
+        self.max_imm_memtables
+    }
+    pub fn block_cache_size(&self) -> usize {
+        self.block_cache_size
+    }
+
+    /// Check the config is usable, it's called by `DB::open`.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // offsets of blocks in a table are stored as u32.
+        let max = u64::from(u32::MAX);
+        if self.max_table_size == 0 || self.max_table_size > max {
+            return Err(ConfigError::InvalidMaxTableSize {
+                size: self.max_table_size,
+                max,
+            });
+        }
+        if self.value_log_file_size == 0 {
+            return Err(ConfigError::InvalidValueLogFileSize);
+        }
+        // value dir can be the same as dir, but not a sub dir of it.
+        if self.value_dir != self.dir && self.value_dir.starts_with(&self.dir) {
+            return Err(ConfigError::NestedValueDir {
+                dir: self.dir.clone(),
+                value_dir: self.value_dir.clone(),
+            });
+        }
+        // writes would be blocked forever if level 0 stops them before it's compacted.
+        if self.level0_slowdown_tables > self.level0_stop_tables
+            || self.level0_stop_tables <= L0_COMPACTION_TRIGGER
+        {
+            return Err(ConfigError::InvalidLevel0Stall {
+                slowdown: self.level0_slowdown_tables,
+                stop: self.level0_stop_tables,
+                min: L0_COMPACTION_TRIGGER,
+            });
+        }
+        if self.max_imm_memtables == 0 {
+            return Err(ConfigError::InvalidMaxImmMemtables);
+        }
+        Ok(())
+    }
+}
+
+pub struct ConfigBuilder {
+    cfg: Config,
+}
+
+impl ConfigBuilder {
+    /// Create a builder with `dir` to store LSM tree files.
+    /// The value log is stored in `dir` too unless `value_dir` is set.
+    pub fn new<P: AsRef<Path>>(dir: P) -> ConfigBuilder {
+        let dir = dir.as_ref().to_path_buf();
+        ConfigBuilder {
+            cfg: Config {
+                value_dir: dir.clone(),
+                dir,
+                sync_write: false,
+                table_loading_mode: TableLoadMode::MemoryMap,
+                value_log_loading_mode: TableLoadMode::MemoryMap,
+                max_table_size: 64 << 20,
+                value_log_file_size: DEFAULT_SEGMENT_MAX_SIZE,
+                bloom_bits_per_key: DEFAULT_BITS_PER_KEY,
+                compaction_style: CompactionStyle::Leveled,
+                level0_slowdown_tables: 8,
+                level0_stop_tables: 12,
+                max_imm_memtables: 4,
+                block_cache_size: 64 << 20,
+            },
+        }
+    }
+
+    /// Directory to store the value log, defaults to `dir`.
+    pub fn value_dir<P: AsRef<Path>>(mut self, value_dir: P) -> ConfigBuilder {
+        self.cfg.value_dir = value_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Sync the value log after each write, defaults to `false`.
+    pub fn sync_write(mut self, sync_write: bool) -> ConfigBuilder {
+        self.cfg.sync_write = sync_write;
+        self
+    }
+
+    /// How tables are loaded, defaults to `TableLoadMode::MemoryMap`.
+    pub fn table_loading_mode(mut self, mode: TableLoadMode) -> ConfigBuilder {
+        self.cfg.table_loading_mode = mode;
+        self
+    }
+
+    /// How value log files are loaded, defaults to `TableLoadMode::MemoryMap`.
+    /// Readonly segments are memory mapped unless it's `TableLoadMode::FileIO`,
+    /// the active segment is always read with positional reads.
+    pub fn value_log_loading_mode(mut self, mode: TableLoadMode) -> ConfigBuilder {
+        self.cfg.value_log_loading_mode = mode;
+        self
+    }
+
+    /// Max size of a table file in bytes, defaults to 64MB, and should not exceed 4GB.
+    pub fn max_table_size(mut self, size: u64) -> ConfigBuilder {
+        self.cfg.max_table_size = size;
+        self
+    }
+
+    /// Max size of a value log file in bytes, defaults to 128MB.
+    pub fn value_log_file_size(mut self, size: u32) -> ConfigBuilder {
+        self.cfg.value_log_file_size = size;
+        self
+    }
+
+    /// Bits per key of bloom filters in tables, defaults to 10, 0 disables filters.
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> ConfigBuilder {
+        self.cfg.bloom_bits_per_key = bits;
+        self
+    }
+
+    /// How tables are compacted, defaults to `CompactionStyle::Leveled`.
+    pub fn compaction_style(mut self, style: CompactionStyle) -> ConfigBuilder {
+        self.cfg.compaction_style = style;
+        self
+    }
+
+    /// Delay each write a little once level 0 has this many tables, defaults to 8.
+    pub fn level0_slowdown_tables(mut self, tables: usize) -> ConfigBuilder {
+        self.cfg.level0_slowdown_tables = tables;
+        self
+    }
+
+    /// Block writes until level 0 is compacted once it has this many tables, defaults to 12.
+    pub fn level0_stop_tables(mut self, tables: usize) -> ConfigBuilder {
+        self.cfg.level0_stop_tables = tables;
+        self
+    }
+
+    /// Block writes until a memtable is flushed once this many are waiting, defaults to 4.
+    pub fn max_imm_memtables(mut self, count: usize) -> ConfigBuilder {
+        self.cfg.max_imm_memtables = count;
+        self
+    }
+
+    /// Capacity in bytes of the block cache shared by tables loaded with `TableLoadMode::FileIO`,
+    /// defaults to 64MB.
+    pub fn block_cache_size(mut self, size: usize) -> ConfigBuilder {
+        self.cfg.block_cache_size = size;
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.cfg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let cfg = ConfigBuilder::new("/tmp/db").build();
+        assert_eq!(Path::new("/tmp/db"), cfg.dir());
+        assert_eq!(cfg.dir(), cfg.value_dir());
+        assert!(!cfg.sync_write());
+        assert_eq!(TableLoadMode::MemoryMap, cfg.table_loading_mode());
+        assert_eq!(64 << 20, cfg.max_table_size());
+        assert_eq!(DEFAULT_BITS_PER_KEY, cfg.bloom_bits_per_key());
+        assert_eq!(CompactionStyle::Leveled, cfg.compaction_style());
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_max_table_size() {
+        let cfg = ConfigBuilder::new("/tmp/db").max_table_size(0).build();
+        assert!(cfg.validate().is_err());
+        let cfg = ConfigBuilder::new("/tmp/db")
+            .max_table_size(u64::from(u32::MAX) + 1)
+            .build();
+        assert!(cfg.validate().is_err());
+        let cfg = ConfigBuilder::new("/tmp/db")
+            .max_table_size(u64::from(u32::MAX))
+            .build();
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_value_dir() {
+        let cfg = ConfigBuilder::new("/tmp/db")
+            .value_dir("/tmp/db/vlog")
+            .build();
+        assert!(cfg.validate().is_err());
+        let cfg = ConfigBuilder::new("/tmp/db").value_dir("/tmp/vlog").build();
+        assert!(cfg.validate().is_ok());
+        let cfg = ConfigBuilder::new("/tmp/db").value_dir("/tmp/db2").build();
+        assert!(cfg.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_write_stalls() {
+        let cfg = ConfigBuilder::new("/tmp/db")
+            .level0_slowdown_tables(10)
+            .level0_stop_tables(9)
+            .build();
+        assert!(cfg.validate().is_err());
+        let cfg = ConfigBuilder::new("/tmp/db")
+            .level0_slowdown_tables(L0_COMPACTION_TRIGGER)
+            .level0_stop_tables(L0_COMPACTION_TRIGGER)
+            .build();
+        assert!(cfg.validate().is_err());
+        let cfg = ConfigBuilder::new("/tmp/db")
+            .level0_slowdown_tables(L0_COMPACTION_TRIGGER)
+            .level0_stop_tables(L0_COMPACTION_TRIGGER + 1)
+            .build();
+        assert!(cfg.validate().is_ok());
+        let cfg = ConfigBuilder::new("/tmp/db").max_imm_memtables(0).build();
+        assert!(cfg.validate().is_err());
+    }
+}
@@ -0,0 +1,493 @@
+use failure::Error;
+use keys::{compare_keys, key_with_ts, parse_key, parse_ts, KeyRange};
+use lsm::SharedMemTable;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, Bound, VecDeque};
+use std::mem;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use table::iterator::TableIterator;
+use threadpool::ThreadPool;
+use txn::Txn;
+use values::{ValuePointer, ValueStruct};
+use DB;
+
+/// An iterator of keys with timestamps in the order of `keys::compare_keys`, which can seek.
+pub trait SeekIterator: Iterator<Item = (Vec<u8>, ValueStruct)> {
+    /// Seek to the first key >= `key`, so that it's returned by the next call of `next`.
+    fn seek(&mut self, key: &[u8]);
+    /// Seek to the first key.
+    fn rewind(&mut self);
+    /// Get err if any error occurred, the iterator stops on error.
+    fn err(&self) -> Option<&Error> {
+        None
+    }
+}
+
+/// Iterate a memtable without holding its lock,
+/// each step looks up the entry after the last returned key.
+pub struct MemTableIterator {
+    mt: SharedMemTable<ValueStruct>,
+    next: Bound<Vec<u8>>,
+}
+
+impl MemTableIterator {
+    pub fn new(mt: SharedMemTable<ValueStruct>) -> MemTableIterator {
+        MemTableIterator {
+            mt,
+            next: Bound::Unbounded,
+        }
+    }
+}
+
+impl Iterator for MemTableIterator {
+    type Item = (Vec<u8>, ValueStruct);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, vs) = {
+            let mt = self.mt.read().unwrap();
+            let start = match self.next {
+                Bound::Included(ref k) => Bound::Included(&k[..]),
+                Bound::Excluded(ref k) => Bound::Excluded(&k[..]),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            let (key, vs) = mt.first_from(start)?;
+            (key.to_vec(), *vs)
+        };
+        self.next = Bound::Excluded(key.clone());
+        Some((key, vs))
+    }
+}
+
+impl SeekIterator for MemTableIterator {
+    fn seek(&mut self, key: &[u8]) {
+        self.next = Bound::Included(key.to_vec());
+    }
+    fn rewind(&mut self) {
+        self.next = Bound::Unbounded;
+    }
+}
+
+/// Iterate a table whose values are encoded `ValueStruct`s.
+pub struct TableValueIterator {
+    it: TableIterator,
+    err: Option<Error>,
+}
+
+impl TableValueIterator {
+    pub fn new(it: TableIterator) -> TableValueIterator {
+        TableValueIterator { it, err: None }
+    }
+}
+
+impl Iterator for TableValueIterator {
+    type Item = (Vec<u8>, ValueStruct);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.err.is_some() {
+            return None;
+        }
+        let (key, value) = self.it.next()?;
+        match ValueStruct::decode(&mut &value[..]) {
+            Ok(vs) => Some((key, vs)),
+            Err(e) => {
+                self.err = Some(e.into());
+                None
+            }
+        }
+    }
+}
+
+impl SeekIterator for TableValueIterator {
+    fn seek(&mut self, key: &[u8]) {
+        self.err = None;
+        self.it.seek(key);
+    }
+    fn rewind(&mut self) {
+        self.err = None;
+        self.it.reset();
+    }
+    fn err(&self) -> Option<&Error> {
+        self.err.as_ref().or_else(|| self.it.err())
+    }
+}
+
+// The current entry of a source in `MergeIterator`.
+struct Head {
+    key: Vec<u8>,
+    value: ValueStruct,
+    source: usize,
+}
+
+// `BinaryHeap` is a max heap, so the smallest key is the greatest head,
+// and for the same key the head of the first source wins.
+impl Ord for Head {
+    fn cmp(&self, other: &Head) -> Ordering {
+        compare_keys(&other.key, &self.key).then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Head) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Head) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+/// Merge sources into one iterator in the order of `keys::compare_keys`.
+/// Sources should be ordered from the newest to the oldest,
+/// if some sources have the same key with timestamp, only the one in the first source is kept.
+pub struct MergeIterator<'a> {
+    sources: Vec<Box<dyn SeekIterator + 'a>>,
+    heads: BinaryHeap<Head>,
+}
+
+impl<'a> MergeIterator<'a> {
+    /// Create an iterator positioned at the first key.
+    pub fn new(sources: Vec<Box<dyn SeekIterator + 'a>>) -> MergeIterator<'a> {
+        let mut it = MergeIterator {
+            heads: BinaryHeap::with_capacity(sources.len()),
+            sources,
+        };
+        it.rewind();
+        it
+    }
+
+    fn fill(&mut self, source: usize) {
+        if let Some((key, value)) = self.sources[source].next() {
+            self.heads.push(Head { key, value, source });
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIterator<'a> {
+    type Item = (Vec<u8>, ValueStruct);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.err().is_some() {
+            return None;
+        }
+        let head = self.heads.pop()?;
+        self.fill(head.source);
+        while self.heads.peek().filter(|h| h.key == head.key).is_some() {
+            let dup = self.heads.pop().unwrap();
+            self.fill(dup.source);
+        }
+        Some((head.key, head.value))
+    }
+}
+
+impl<'a> SeekIterator for MergeIterator<'a> {
+    fn seek(&mut self, key: &[u8]) {
+        self.heads.clear();
+        for i in 0..self.sources.len() {
+            self.sources[i].seek(key);
+            self.fill(i);
+        }
+    }
+    fn rewind(&mut self) {
+        self.heads.clear();
+        for i in 0..self.sources.len() {
+            self.sources[i].rewind();
+            self.fill(i);
+        }
+    }
+    fn err(&self) -> Option<&Error> {
+        self.sources.iter().filter_map(|s| s.err()).next()
+    }
+}
+
+/// A key of `DBIterator` and a pointer to its value,
+/// the value is read from value log only when `value` is called.
+pub struct Item<'a> {
+    db: &'a DB,
+    key: Vec<u8>,
+    version: u64,
+    pointer: ValuePointer,
+    value: RefCell<ItemValue>,
+}
+
+enum ItemValue {
+    Unread,
+    // being read by a prefetch job.
+    Pending(Receiver<Result<Vec<u8>, Error>>),
+    Read(Vec<u8>),
+}
+
+impl<'a> Item<'a> {
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The commit timestamp of the value.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Get the value, it's read from value log on the first call unless prefetched.
+    pub fn value(&self) -> Result<Vec<u8>, Error> {
+        let mut state = self.value.borrow_mut();
+        let value = match mem::replace(&mut *state, ItemValue::Unread) {
+            ItemValue::Read(v) => v,
+            // the job is gone only if it panicked, read it again.
+            ItemValue::Pending(rx) => rx.recv().unwrap_or_else(|_| self.read())?,
+            ItemValue::Unread => self.read()?,
+        };
+        *state = ItemValue::Read(value.clone());
+        Ok(value)
+    }
+
+    fn read(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.db.core.vlog.read().unwrap().read(&self.pointer)?.value)
+    }
+
+    // Start reading the value in `pool`, values of many items can be read in parallel.
+    fn prefetch(&self, pool: &ThreadPool) {
+        let (tx, rx) = mpsc::channel();
+        let core = Arc::clone(&self.db.core);
+        let pointer = self.pointer;
+        pool.execute(move || {
+            let res = core.vlog.read().unwrap().read(&pointer).map(|v| v.value);
+            // the item may have been dropped.
+            let _ = tx.send(res);
+        });
+        *self.value.borrow_mut() = ItemValue::Pending(rx);
+    }
+}
+
+/// Iterate user keys in a range of a snapshot of the DB,
+/// only the newest visible version of each key is returned, and deleted keys are skipped.
+/// Values are not read unless asked for, see `Item::value` and `prefetch`.
+pub struct DBIterator<'a> {
+    db: &'a DB,
+    // keep the snapshot alive.
+    txn: Txn<'a>,
+    iter: MergeIterator<'a>,
+    range: KeyRange,
+    item: Option<Item<'a>>,
+    // items after the current one, whose values may be being prefetched.
+    ahead: VecDeque<Item<'a>>,
+    // user key of the last version taken from `iter`.
+    last_key: Option<Vec<u8>>,
+    prefetch_size: usize,
+}
+
+impl<'a> DBIterator<'a> {
+    // `db.vlog_readers` should be increased for the iterator, it's decreased on drop.
+    pub(crate) fn new(
+        db: &'a DB,
+        txn: Txn<'a>,
+        iter: MergeIterator<'a>,
+        range: KeyRange,
+    ) -> DBIterator<'a> {
+        let mut it = DBIterator {
+            db,
+            txn,
+            iter,
+            range,
+            item: None,
+            ahead: VecDeque::new(),
+            last_key: None,
+            prefetch_size: 0,
+        };
+        it.rewind();
+        it
+    }
+
+    /// Read values of the next `size` items in parallel ahead of the iterator,
+    /// 0 disables prefetching. The iterator is rewound.
+    pub fn prefetch(mut self, size: usize) -> DBIterator<'a> {
+        self.prefetch_size = size;
+        self.rewind();
+        self
+    }
+
+    /// Seek to the first key in the range.
+    pub fn rewind(&mut self) {
+        match self.range.lower() {
+            Bound::Included(k) | Bound::Excluded(k) => {
+                let key = key_with_ts(k, self.txn.read_ts());
+                self.iter.seek(&key);
+            }
+            Bound::Unbounded => self.iter.rewind(),
+        }
+        self.reset();
+    }
+
+    /// Seek to the first key >= `key` in the range.
+    pub fn seek(&mut self, key: &[u8]) {
+        if self.range.is_before(key) {
+            return self.rewind();
+        }
+        self.iter.seek(&key_with_ts(key, self.txn.read_ts()));
+        self.reset();
+    }
+
+    /// Whether the iterator is at an item, it's false if no key left or an error occurred.
+    pub fn valid(&self) -> bool {
+        self.item.is_some()
+    }
+
+    /// The current item, the iterator should be valid.
+    pub fn item(&self) -> &Item<'a> {
+        self.item.as_ref().expect("iterator is not valid")
+    }
+
+    /// The current key, the iterator should be valid.
+    pub fn key(&self) -> &[u8] {
+        self.item().key()
+    }
+
+    /// Read the current value, the iterator should be valid.
+    pub fn value(&self) -> Result<Vec<u8>, Error> {
+        self.item().value()
+    }
+
+    /// Move to the next key.
+    pub fn next(&mut self) {
+        // the next item is taken even if prefetching is disabled.
+        while self.ahead.len() < self.prefetch_size.max(1) {
+            match self.take_visible() {
+                Some(item) => {
+                    if self.prefetch_size > 0 {
+                        item.prefetch(&self.db.core.prefetch_pool);
+                    }
+                    self.ahead.push_back(item);
+                }
+                None => break,
+            }
+        }
+        self.item = self.ahead.pop_front();
+    }
+
+    /// Get err if any error occurred.
+    pub fn err(&self) -> Option<&Error> {
+        self.iter.err()
+    }
+
+    // Drop items taken before `iter` is repositioned.
+    fn reset(&mut self) {
+        self.ahead.clear();
+        self.last_key = None;
+        self.next();
+    }
+
+    // Take the newest version not newer than the snapshot of the next key after `last_key`.
+    fn take_visible(&mut self) -> Option<Item<'a>> {
+        let read_ts = self.txn.read_ts();
+        for (key, vs) in self.iter.by_ref() {
+            let user_key = parse_key(&key);
+            if self.range.is_after(user_key) {
+                return None;
+            }
+            // an older version of the last key.
+            if self.last_key.as_ref().filter(|l| l.as_slice() == user_key).is_some() {
+                continue;
+            }
+            if self.range.is_before(user_key) {
+                continue;
+            }
+            let version = parse_ts(&key);
+            if version > read_ts {
+                continue;
+            }
+            self.last_key = Some(user_key.to_vec());
+            if vs.is_deleted() {
+                continue;
+            }
+            return Some(Item {
+                db: self.db,
+                key: user_key.to_vec(),
+                version,
+                pointer: vs.pointer,
+                value: RefCell::new(ItemValue::Unread),
+            });
+        }
+        None
+    }
+}
+
+impl<'a> Drop for DBIterator<'a> {
+    fn drop(&mut self) {
+        self.db.done_vlog_read();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A source of entries in a vec.
+    struct VecIterator {
+        entries: Vec<(Vec<u8>, ValueStruct)>,
+        pos: usize,
+    }
+
+    impl Iterator for VecIterator {
+        type Item = (Vec<u8>, ValueStruct);
+        fn next(&mut self) -> Option<Self::Item> {
+            let e = self.entries.get(self.pos).cloned();
+            self.pos += 1;
+            e
+        }
+    }
+
+    impl SeekIterator for VecIterator {
+        fn seek(&mut self, key: &[u8]) {
+            self.pos = self.entries
+                .iter()
+                .position(|e| compare_keys(&e.0, key) != Ordering::Less)
+                .unwrap_or(self.entries.len());
+        }
+        fn rewind(&mut self) {
+            self.pos = 0;
+        }
+    }
+
+    fn source<'a>(entries: &[(&[u8], u64, u8)]) -> Box<dyn SeekIterator + 'a> {
+        Box::new(VecIterator {
+            entries: entries
+                .iter()
+                .map(|&(k, ts, meta)| {
+                    let vs = ValueStruct::new(meta, Default::default());
+                    (key_with_ts(k, ts), vs)
+                })
+                .collect(),
+            pos: 0,
+        })
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut it = MergeIterator::new(vec![
+            source(&[(b"a", 3, 1), (b"c", 2, 1)]),
+            source(&[(b"a", 2, 0), (b"b", 1, 0), (b"c", 2, 0)]),
+            source(&[]),
+            source(&[(b"a", 3, 0), (b"d", 1, 0)]),
+        ]);
+        let expected = vec![
+            (key_with_ts(b"a", 3), 1),
+            (key_with_ts(b"a", 2), 0),
+            (key_with_ts(b"b", 1), 0),
+            (key_with_ts(b"c", 2), 1),
+            (key_with_ts(b"d", 1), 0),
+        ];
+        let merged: Vec<_> = it.by_ref().map(|(k, vs)| (k, vs.meta)).collect();
+        assert_eq!(expected, merged);
+
+        it.seek(&key_with_ts(b"a", 1));
+        let merged: Vec<_> = it.by_ref().map(|(k, vs)| (k, vs.meta)).collect();
+        assert_eq!(&expected[2..], &merged[..]);
+
+        it.rewind();
+        assert_eq!(Some(key_with_ts(b"a", 3)), it.next().map(|e| e.0));
+    }
+}
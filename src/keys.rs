@@ -0,0 +1,213 @@
+//! Internal keys are user keys suffixed with a version, the commit timestamp.
+//! The timestamp is stored as `u64::MAX - ts` in big endian,
+//! so that for the same user key, newer versions are ordered before older ones.
+use byteorder::{BigEndian, ByteOrder};
+use std::cmp::Ordering;
+use std::collections::Bound;
+use std::ops::RangeBounds;
+
+pub const TS_SIZE: usize = 8;
+
+pub fn key_with_ts(key: &[u8], ts: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(key.len() + TS_SIZE);
+    out.extend_from_slice(key);
+    out.resize(key.len() + TS_SIZE, 0);
+    BigEndian::write_u64(&mut out[key.len()..], u64::MAX - ts);
+    out
+}
+
+/// Get the user key part of an internal key.
+pub fn parse_key(key: &[u8]) -> &[u8] {
+    if key.len() < TS_SIZE {
+        return key;
+    }
+    &key[..key.len() - TS_SIZE]
+}
+
+/// Get the timestamp of an internal key.
+pub fn parse_ts(key: &[u8]) -> u64 {
+    if key.len() < TS_SIZE {
+        return 0;
+    }
+    u64::MAX - BigEndian::read_u64(&key[key.len() - TS_SIZE..])
+}
+
+/// Order internal keys by user key first, then by timestamp in descending order.
+pub fn compare_keys(a: &[u8], b: &[u8]) -> Ordering {
+    parse_key(a)
+        .cmp(parse_key(b))
+        .then_with(|| parse_ts(b).cmp(&parse_ts(a)))
+}
+
+/// Check if two internal keys have the same user key.
+pub fn same_key(a: &[u8], b: &[u8]) -> bool {
+    parse_key(a) == parse_key(b)
+}
+
+/// A range of user keys with inclusive or exclusive bounds.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyRange {
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+}
+
+impl KeyRange {
+    pub fn new<K: AsRef<[u8]>, R: RangeBounds<K>>(range: R) -> KeyRange {
+        let to_vec = |b: Bound<&K>| match b {
+            Bound::Included(k) => Bound::Included(k.as_ref().to_vec()),
+            Bound::Excluded(k) => Bound::Excluded(k.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        KeyRange {
+            lower: to_vec(range.start_bound()),
+            upper: to_vec(range.end_bound()),
+        }
+    }
+
+    /// The range of all keys.
+    pub fn full() -> KeyRange {
+        KeyRange {
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        }
+    }
+
+    /// The range of keys starting with `prefix`.
+    pub fn prefix(prefix: &[u8]) -> KeyRange {
+        // the upper bound is the prefix with the last byte which is not 0xff increased.
+        let upper = match prefix.iter().rposition(|&b| b != 0xff) {
+            Some(i) => {
+                let mut upper = prefix[..=i].to_vec();
+                upper[i] += 1;
+                Bound::Excluded(upper)
+            }
+            None => Bound::Unbounded,
+        };
+        KeyRange {
+            lower: Bound::Included(prefix.to_vec()),
+            upper,
+        }
+    }
+
+    pub fn lower(&self) -> Bound<&[u8]> {
+        as_slice(&self.lower)
+    }
+
+    pub fn upper(&self) -> Bound<&[u8]> {
+        as_slice(&self.upper)
+    }
+
+    /// Check if the user key `key` is less than the lower bound.
+    pub fn is_before(&self, key: &[u8]) -> bool {
+        match self.lower {
+            Bound::Included(ref l) => key < &l[..],
+            Bound::Excluded(ref l) => key <= &l[..],
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// Check if the user key `key` is greater than the upper bound.
+    pub fn is_after(&self, key: &[u8]) -> bool {
+        match self.upper {
+            Bound::Included(ref u) => key > &u[..],
+            Bound::Excluded(ref u) => key >= &u[..],
+            Bound::Unbounded => false,
+        }
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        !self.is_before(key) && !self.is_after(key)
+    }
+
+    /// Check if any user key in `[smallest, biggest]` is in the range.
+    pub fn overlaps(&self, smallest: &[u8], biggest: &[u8]) -> bool {
+        !self.is_after(smallest) && !self.is_before(biggest)
+    }
+}
+
+fn as_slice(b: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match *b {
+        Bound::Included(ref k) => Bound::Included(k),
+        Bound::Excluded(ref k) => Bound::Excluded(k),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_with_ts() {
+        let key = key_with_ts(b"key", 10);
+        assert_eq!(3 + TS_SIZE, key.len());
+        assert_eq!(b"key", parse_key(&key));
+        assert_eq!(10, parse_ts(&key));
+    }
+
+    #[test]
+    fn test_compare_keys() {
+        // newer version first.
+        assert_eq!(
+            Ordering::Less,
+            compare_keys(&key_with_ts(b"a", 2), &key_with_ts(b"a", 1))
+        );
+        assert_eq!(
+            Ordering::Equal,
+            compare_keys(&key_with_ts(b"a", 2), &key_with_ts(b"a", 2))
+        );
+        // user key first, even if one is a prefix of the other.
+        assert_eq!(
+            Ordering::Less,
+            compare_keys(&key_with_ts(b"a", 1), &key_with_ts(b"ab", 2))
+        );
+        assert_eq!(
+            Ordering::Less,
+            compare_keys(&key_with_ts(b"a", 0), &key_with_ts(b"ab", u64::MAX))
+        );
+        assert!(same_key(&key_with_ts(b"a", 1), &key_with_ts(b"a", 2)));
+        assert!(!same_key(&key_with_ts(b"a", 1), &key_with_ts(b"ab", 1)));
+    }
+
+    #[test]
+    fn test_key_range() {
+        let r = KeyRange::new(&b"b"[..]..&b"d"[..]);
+        assert!(r.is_before(b"a"));
+        assert!(r.contains(b"b"));
+        assert!(r.contains(b"cc"));
+        assert!(r.is_after(b"d"));
+        assert!(r.overlaps(b"a", b"b"));
+        assert!(!r.overlaps(b"a", b"az"));
+        assert!(!r.overlaps(b"d", b"e"));
+
+        let r = KeyRange::new::<&[u8], _>((
+            Bound::Excluded(&b"b"[..]),
+            Bound::Included(&b"d"[..]),
+        ));
+        assert!(r.is_before(b"b"));
+        assert!(r.contains(b"d"));
+        assert!(!r.overlaps(b"a", b"b"));
+        assert!(r.overlaps(b"d", b"e"));
+
+        let r = KeyRange::full();
+        assert!(r.contains(b""));
+        assert_eq!(r, KeyRange::new::<&[u8], _>(..));
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        let r = KeyRange::prefix(b"ab");
+        assert_eq!(Bound::Included(&b"ab"[..]), r.lower());
+        assert_eq!(Bound::Excluded(&b"ac"[..]), r.upper());
+        assert!(r.contains(b"ab"));
+        assert!(r.contains(b"ab\xff"));
+        assert!(!r.contains(b"ac"));
+        assert!(!r.contains(b"a"));
+
+        let r = KeyRange::prefix(b"a\xff\xff");
+        assert_eq!(Bound::Excluded(&b"b"[..]), r.upper());
+        assert!(r.contains(b"a\xff\xff\x01"));
+        assert_eq!(Bound::Unbounded, KeyRange::prefix(b"\xff").upper());
+        assert_eq!(KeyRange::full().upper(), KeyRange::prefix(b"").upper());
+    }
+}
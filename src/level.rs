@@ -1,11 +1,19 @@
+use failure::Error;
+use keys::{compare_keys, key_with_ts, parse_key};
+use std::sync::Arc;
 use table::Table;
+use values::ValueStruct;
 
+/// Number of tables in level 0 which triggers a compaction into level 1.
+pub const L0_COMPACTION_TRIGGER: usize = 4;
+
+#[derive(Clone)]
 pub struct LevelHandler {
     // initialize once
     level: u32,
     max_total_size: u64,
 
-    tables: Vec<Table>,
+    tables: Vec<Arc<Table>>,
     size: u64,
 }
 
@@ -19,4 +27,162 @@ impl LevelHandler {
             tables: vec![],
         }
     }
+
+    #[inline]
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+    #[inline]
+    pub fn max_total_size(&self) -> u64 {
+        self.max_total_size
+    }
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    #[inline]
+    pub fn tables(&self) -> &[Arc<Table>] {
+        &self.tables
+    }
+
+    /// Set tables of the level loaded on open,
+    /// tables in level 0 are ordered by id, others by keys.
+    pub fn init_tables(&mut self, mut tables: Vec<Arc<Table>>) {
+        if self.level == 0 {
+            tables.sort_by_key(|t| t.id());
+        } else {
+            tables.sort_by(|a, b| compare_keys(a.smallest(), b.smallest()));
+        }
+        self.size = tables.iter().map(|t| t.size()).sum();
+        self.tables = tables;
+    }
+
+    pub fn add_table(&mut self, t: Arc<Table>) {
+        self.size += t.size();
+        self.tables.push(t);
+    }
+
+    /// Remove tables with ids in `removed` and add `added`, which are the result of a compaction.
+    pub fn replace_tables(&mut self, removed: &[u64], added: Vec<Arc<Table>>) {
+        let mut tables: Vec<Arc<Table>> = self.tables
+            .drain(..)
+            .filter(|t| !removed.contains(&t.id()))
+            .collect();
+        tables.extend(added);
+        self.init_tables(tables);
+    }
+
+    /// How much the level needs a compaction, it needs one if the score is at least 1.
+    /// Level 0 is scored by the number of tables, since each of them is searched by reads.
+    pub fn score(&self) -> f64 {
+        if self.level == 0 {
+            self.tables.len() as f64 / L0_COMPACTION_TRIGGER as f64
+        } else {
+            self.size as f64 / self.max_total_size as f64
+        }
+    }
+
+    /// Tables which may hold user keys in `[smallest, biggest]`.
+    pub fn overlapping(&self, smallest: &[u8], biggest: &[u8]) -> Vec<Arc<Table>> {
+        self.tables
+            .iter()
+            .filter(|t| parse_key(t.smallest()) <= biggest && smallest <= parse_key(t.biggest()))
+            .cloned()
+            .collect()
+    }
+
+    // Check if any table in this level may hold some version of the user key `key`.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.tables
+            .iter()
+            .any(|t| parse_key(t.smallest()) <= key && key <= parse_key(t.biggest()))
+    }
+
+    // Get the newest version of `key` not newer than `read_ts` in this level,
+    // the result is the version and the value struct.
+    // Tables in level 0 may overlap with each other, newer tables are appended at the end,
+    // so they are searched from back to front.
+    pub fn get(&self, key: &[u8], read_ts: u64) -> Result<Option<(u64, ValueStruct)>, Error> {
+        let seek_key = key_with_ts(key, read_ts);
+        for t in self.tables.iter().rev() {
+            if parse_key(t.smallest()) > key || key > parse_key(t.biggest()) {
+                continue;
+            }
+            if let Some(found) = t.get_value_struct(&seek_key)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A tombstone of `key` compacted into `levels[level]` can be dropped
+/// only if no lower level may still hold an older version of the key,
+/// or else the older version would be visible again.
+pub fn can_drop_tombstone(levels: &[LevelHandler], level: usize, key: &[u8]) -> bool {
+    levels
+        .iter()
+        .skip(level + 1)
+        .all(|l| !l.may_contain(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use table::tests::build_table_with_id;
+
+    fn table(keys: &[(&[u8], u64)]) -> Arc<Table> {
+        table_with_id(1, keys)
+    }
+
+    fn table_with_id(id: u64, keys: &[(&[u8], u64)]) -> Arc<Table> {
+        build_table_with_id(id, &[keys
+            .iter()
+            .map(|&(k, ts)| (key_with_ts(k, ts), vec![]))
+            .collect()])
+    }
+
+    #[test]
+    fn test_can_drop_tombstone() {
+        let mut levels: Vec<LevelHandler> = (0..3).map(|l| LevelHandler::new(l, 1024)).collect();
+        levels[2].add_table(table(&[(b"b", 1), (b"d", 1)]));
+        assert!(levels[2].may_contain(b"b"));
+        assert!(levels[2].may_contain(b"c"));
+        assert!(!levels[2].may_contain(b"a"));
+        assert!(!levels[2].may_contain(b"e"));
+
+        assert!(can_drop_tombstone(&levels, 1, b"a"));
+        assert!(!can_drop_tombstone(&levels, 1, b"c"));
+        assert!(!can_drop_tombstone(&levels, 0, b"c"));
+        // nothing below the last level.
+        assert!(can_drop_tombstone(&levels, 2, b"c"));
+    }
+
+    #[test]
+    fn test_score_and_replace_tables() {
+        let mut l0 = LevelHandler::new(0, 1024);
+        for _ in 0..L0_COMPACTION_TRIGGER - 1 {
+            l0.add_table(table(&[(b"a", 1)]));
+        }
+        assert!(l0.score() < 1.0);
+        l0.add_table(table(&[(b"a", 2)]));
+        assert!(l0.score() >= 1.0);
+
+        let mut l1 = LevelHandler::new(1, 1 << 20);
+        let t1 = table_with_id(1, &[(b"c", 1), (b"d", 1)]);
+        let t2 = table_with_id(2, &[(b"a", 1), (b"b", 1)]);
+        l1.add_table(Arc::clone(&t1));
+        l1.add_table(Arc::clone(&t2));
+        assert!(l1.score() < 1.0);
+        assert_eq!(1, l1.overlapping(b"b", b"b").len());
+        assert_eq!(2, l1.overlapping(b"b", b"c").len());
+        assert!(l1.overlapping(b"e", b"f").is_empty());
+
+        let t3 = table_with_id(3, &[(b"e", 1)]);
+        l1.replace_tables(&[t1.id()], vec![Arc::clone(&t3), Arc::clone(&t1)]);
+        // sorted by keys.
+        let tables: Vec<&[u8]> = l1.tables().iter().map(|t| t.smallest()).collect();
+        assert_eq!(vec![t2.smallest(), t1.smallest(), t3.smallest()], tables);
+        assert_eq!(t1.size() + t2.size() + t3.size(), l1.size());
+    }
 }
@@ -1,44 +1,1269 @@
-#![feature(option_filter)]
 extern crate byteorder;
 extern crate bytes;
 #[macro_use]
 extern crate failure;
 extern crate memmap;
-extern crate serde;
-#[macro_use]
-extern crate serde_derive;
+extern crate tempdir;
+extern crate threadpool;
 
+use compaction::CompactionPicker;
+use failure::Error;
+use iterator::{DBIterator, MemTableIterator, MergeIterator, SeekIterator, TableValueIterator};
+use keys::{key_with_ts, parse_key, parse_ts, KeyRange};
+use level::LevelHandler;
+use lsm::LSM;
+use manifest::{ManifestChange, ManifestFile};
 use std::fs;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use table::builder::{TableBuilder, DEFAULT_BLOCK_SIZE};
+use table::cache::BlockCache;
+use table::{parse_table_id, table_path, Table};
+use threadpool::ThreadPool;
+use txn::{Oracle, Txn};
+use values::{GcAction, Value, ValueLog, ValueOption, ValuePointer, ValueStruct, BIT_FIN_TXN,
+             BIT_TXN};
 
+pub mod iterator;
+pub mod keys;
 pub mod table;
 pub mod level;
 pub mod txn;
 pub mod values;
+mod compaction;
+mod config;
 mod lsm;
+mod manifest;
+mod stats;
+
+pub use compaction::CompactionStyle;
+pub use config::{Config, ConfigBuilder, ConfigError};
+pub use stats::Stats;
+
+const MAX_LEVELS: u32 = 7;
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+const PREFETCH_THREADS: usize = 4;
+// Key of the entry marking the end of a transaction in value log.
+const TXN_KEY: &[u8] = b"!spiderdb!txn";
+
+/// `DB` keeps keys in the LSM tree and values in the value log,
+/// the LSM tree only stores a `ValuePointer` of each value.
+pub struct DB {
+    core: Arc<Core>,
+}
+
+// State of `DB` shared with background jobs.
+struct Core {
+    cfg: Config,
+    lsm: RwLock<LSM<ValueStruct>>,
+    levels: RwLock<Vec<LevelHandler>>,
+    manifest: Mutex<ManifestFile>,
+    next_table_id: AtomicU64,
+    // blocks of tables opened with `TableLoadMode::FileIO`.
+    block_cache: Arc<BlockCache>,
+    // reads and appends take the read lock, appends are serialized inside value log,
+    // only gc and deleting collected segments take the write lock.
+    vlog: RwLock<ValueLog>,
+    // number of readers which may hold pointers into value log.
+    vlog_readers: AtomicUsize,
+    orc: Oracle,
+    // reads values ahead of iterators.
+    prefetch_pool: ThreadPool,
+    // flushes frozen memtables to level 0 one by one.
+    flush_pool: ThreadPool,
+    // merges tables into lower levels one compaction at a time.
+    compact_pool: ThreadPool,
+    picker: Box<dyn CompactionPicker>,
+    stats: Mutex<Stats>,
+    // the last error of background jobs, returned by the next write.
+    bg_err: Mutex<Option<Error>>,
+    // notified when background jobs make progress or fail, waited by stalled writes.
+    bg_cond: Condvar,
+}
+
+impl DB {
+    pub fn open(cfg: &Config) -> Result<DB, Error> {
+        cfg.validate()?;
+        fs::create_dir_all(&cfg.dir)?;
+        fs::create_dir_all(&cfg.value_dir)?;
+
+        let manifest = ManifestFile::open(&cfg.dir)?;
+        let mut vlog = ValueLog::open(&ValueOption::new(
+            &cfg.value_dir,
+            cfg.value_log_file_size,
+            cfg.sync_write,
+            cfg.value_log_loading_mode,
+        ))?;
+        // the head in manifest is persisted along with tables, the one of value log may lag.
+        let head = manifest.manifest().head;
+        if vlog.head() != head {
+            vlog.set_head(head)?;
+        }
+
+        // value log is the write ahead log, rebuild memtable with entries not in tables yet.
+        let mut lsm = LSM::new(cfg.max_table_size as u32);
+        let mut max_ts = manifest.manifest().max_version;
+        vlog.replay(|v, p| {
+            let ts = parse_ts(&v.key);
+            lsm.write(parse_key(&v.key), ts, ValueStruct::new(v.meta, p));
+            if lsm.is_full() {
+                lsm.freeze();
+            }
+            max_ts = max_ts.max(ts);
+            Ok(())
+        })?;
+
+        let block_cache = Arc::new(BlockCache::new(cfg.block_cache_size));
+        let mut tables = vec![vec![]; MAX_LEVELS as usize];
+        for (&id, tm) in manifest.manifest().tables.iter() {
+            let fd = fs::File::open(table_path(&cfg.dir, id))?;
+            let cache = Some(Arc::clone(&block_cache));
+            let t = Table::open_with_cache(id, fd, cfg.table_loading_mode, cache)?;
+            tables[tm.level as usize].push(Arc::new(t));
+        }
+        let mut levels = Vec::with_capacity(MAX_LEVELS as usize);
+        let mut max_total_size = cfg.max_table_size;
+        for (level, tables) in tables.into_iter().enumerate() {
+            let mut handler = LevelHandler::new(level as u32, max_total_size);
+            handler.init_tables(tables);
+            levels.push(handler);
+            max_total_size *= LEVEL_SIZE_MULTIPLIER;
+        }
+        // tables not in manifest are left by flushes not finished.
+        // they are kept if the manifest is just truncated, until it opens cleanly.
+        if !manifest.truncated() {
+            for entry in fs::read_dir(&cfg.dir)? {
+                let entry = entry?;
+                if let Some(id) = entry.file_name().to_str().and_then(parse_table_id) {
+                    if !manifest.manifest().tables.contains_key(&id) {
+                        fs::remove_file(entry.path())?;
+                    }
+                }
+            }
+        }
+        let next_table_id = manifest.manifest().next_file_id.max(1);
+
+        let has_imm = lsm.imm_count() > 0;
+        let core = Arc::new(Core {
+            cfg: cfg.clone(),
+            lsm: RwLock::new(lsm),
+            levels: RwLock::new(levels),
+            manifest: Mutex::new(manifest),
+            next_table_id: AtomicU64::new(next_table_id),
+            block_cache,
+            vlog: RwLock::new(vlog),
+            vlog_readers: AtomicUsize::new(0),
+            orc: Oracle::new(max_ts + 1),
+            prefetch_pool: ThreadPool::with_name("prefetch".to_owned(), PREFETCH_THREADS),
+            flush_pool: ThreadPool::with_name("flush".to_owned(), 1),
+            compact_pool: ThreadPool::with_name("compact".to_owned(), 1),
+            picker: compaction::new_picker(cfg.compaction_style),
+            stats: Mutex::new(Stats::default()),
+            bg_err: Mutex::new(None),
+            bg_cond: Condvar::new(),
+        });
+        if has_imm {
+            core.schedule_flush();
+        }
+        if core.picker.pick(&core.levels.read().unwrap()).is_some() {
+            core.schedule_compaction();
+        }
+        Ok(DB { core })
+    }
+
+    /// Counters of flushes and compactions since the `DB` is opened.
+    pub fn stats(&self) -> Stats {
+        self.core.stats.lock().unwrap().clone()
+    }
+
+    /// Start a new transaction reading the latest committed data.
+    pub fn begin(&self, read_only: bool) -> Txn<'_> {
+        Txn::new(self, read_only)
+    }
+
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let mut txn = self.begin(false);
+        txn.set(key, value)?;
+        txn.commit()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        self.begin(true).get(key)
+    }
+
+    // Get the newest version of `key` not newer than `read_ts`.
+    fn get_with_ts(&self, key: &[u8], read_ts: u64) -> Result<Option<Vec<u8>>, Error> {
+        self.core.vlog_readers.fetch_add(1, Ordering::SeqCst);
+        let res = self.read_value(key, read_ts);
+        self.done_vlog_read();
+        res
+    }
+
+    fn read_value(&self, key: &[u8], read_ts: u64) -> Result<Option<Vec<u8>>, Error> {
+        let vs = match self.get_value_struct(key, read_ts)? {
+            Some((_, ref vs)) if vs.is_deleted() => return Ok(None),
+            Some((_, vs)) => vs,
+            None => return Ok(None),
+        };
+        let value = self.core.vlog.read().unwrap().read(&vs.pointer)?;
+        Ok(Some(value.value))
+    }
+
+    // Segments collected by gc can be deleted when the last reader is done.
+    fn done_vlog_read(&self) {
+        if self.core.vlog_readers.fetch_sub(1, Ordering::SeqCst) == 1
+            && self.core.vlog.read().unwrap().has_pending_deletes()
+        {
+            let mut vlog = self.core.vlog.write().unwrap();
+            if vlog.has_pending_deletes() && self.core.vlog_readers.load(Ordering::SeqCst) == 0 {
+                // it's fine to retry on the next read if it fails.
+                let _ = vlog.delete_pending_segments();
+            }
+        }
+    }
+
+    /// Iterate the latest committed data from the first key.
+    pub fn iter(&self) -> DBIterator<'_> {
+        self.iter_range(KeyRange::full())
+    }
+
+    /// Iterate keys in `range` of the latest committed data,
+    /// e.g. `db.range(&b"a"[..]..&b"c"[..])`.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> DBIterator<'_> {
+        self.iter_range(KeyRange::new(range))
+    }
+
+    /// Iterate keys starting with `prefix` of the latest committed data.
+    pub fn prefix(&self, prefix: &[u8]) -> DBIterator<'_> {
+        self.iter_range(KeyRange::prefix(prefix))
+    }
+
+    fn iter_range(&self, range: KeyRange) -> DBIterator<'_> {
+        // values are read from value log until the iterator is dropped,
+        // count it before taking sources, so that gc can't delete segments they point into.
+        self.core.vlog_readers.fetch_add(1, Ordering::SeqCst);
+        // newer sources first, tables in a level are appended from old to new.
+        let mut sources: Vec<Box<dyn SeekIterator>> = vec![];
+        for mt in self.core.lsm.read().unwrap().memtables() {
+            sources.push(Box::new(MemTableIterator::new(mt)));
+        }
+        for level in self.core.levels.read().unwrap().iter() {
+            for t in level.tables().iter().rev() {
+                if !range.overlaps(parse_key(t.smallest()), parse_key(t.biggest())) {
+                    continue;
+                }
+                let mut it = t.iter();
+                it.set_range(range.clone());
+                sources.push(Box::new(TableValueIterator::new(it)));
+            }
+        }
+        DBIterator::new(self, self.begin(true), MergeIterator::new(sources), range)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<(), Error> {
+        let mut txn = self.begin(false);
+        txn.delete(key)?;
+        txn.commit()
+    }
+
+    // Apply writes of a committed transaction with version `commit_ts`,
+    // a `None` value deletes the key by writing a tombstone.
+    // Values are written into value log in one batch followed by a transaction end marker,
+    // so that a transaction partially written before a crash is ignored on replay.
+    // Then their pointers are inserted into memtable while holding the write lock,
+    // so that readers see either all or none of them.
+    fn write_entries(
+        &self,
+        commit_ts: u64,
+        entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<(), Error> {
+        if let Some(e) = self.core.bg_err.lock().unwrap().take() {
+            return Err(e);
+        }
+        let mut values: Vec<Value> = entries
+            .iter()
+            .map(|(k, v)| {
+                let key = key_with_ts(k, commit_ts);
+                let mut value = match *v {
+                    Some(ref v) => Value::new(&key, v),
+                    None => Value::tombstone(&key),
+                };
+                value.meta |= BIT_TXN;
+                value
+            })
+            .collect();
+        let mut fin = Value::new(&key_with_ts(TXN_KEY, commit_ts), &[]);
+        fin.meta = BIT_FIN_TXN;
+        values.push(fin);
+        // the newest versions before this commit are obsolete now,
+        // look them up before appending to value log.
+        let mut discarded = Vec::with_capacity(entries.len());
+        for (k, _) in entries.iter() {
+            if let Some((_, vs)) = self.get_value_struct(k, u64::MAX)? {
+                discarded.push(vs.pointer);
+            }
+        }
+        // hold the value log lock until the pointers are in memtable, so gc can't rewrite
+        // entries in between and the order in memtable is the same as the order in value log.
+        let vlog = self.core.vlog.read().unwrap();
+        let pointers = vlog.write(&values)?;
+        vlog.update_discard_stats(&discarded);
+        let mut lsm = self.core.lsm.write().unwrap();
+        for ((k, _), (v, p)) in entries.iter().zip(values.iter().zip(pointers)) {
+            lsm.write(k, commit_ts, ValueStruct::new(v.meta, p));
+        }
+        self.core.freeze_if_full(&mut lsm);
+        Ok(())
+    }
+
+    // Find the value struct of `key` at `read_ts` and its version,
+    // search memtable first, then each level from top to bottom.
+    // Newer versions are always in upper levels, so the first one found is the newest,
+    // even if it's a tombstone.
+    fn get_value_struct(
+        &self,
+        key: &[u8],
+        read_ts: u64,
+    ) -> Result<Option<(u64, ValueStruct)>, Error> {
+        if let Some(found) = self.core.lsm.read().unwrap().get(key, read_ts) {
+            return Ok(Some(found));
+        }
+        for level in self.core.levels.read().unwrap().iter() {
+            if let Some(found) = level.get(key, read_ts)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// Impl memtable flush
+impl Core {
+    // Freeze the memtable if it's full, and flush it in background.
+    fn freeze_if_full(self: &Arc<Self>, lsm: &mut LSM<ValueStruct>) {
+        if lsm.is_full() {
+            lsm.freeze();
+            self.schedule_flush();
+        }
+    }
+
+    fn schedule_flush(self: &Arc<Self>) {
+        let core = Arc::clone(self);
+        self.flush_pool.execute(move || {
+            match core.flush_memtables() {
+                Ok(()) => core.schedule_compaction(),
+                Err(e) => core.set_bg_err(e),
+            }
+        });
+    }
 
-pub struct Config {
-    dir: String,
-    value_dir: String,
-    sync_write: bool,
-    table_loading_mode: u8,
-    value_log_loading_mode: u8,
-    max_table_size: u64,
+    // Flush frozen memtables into level 0 from the oldest, so that newer tables are added later.
+    // It stops at the first failure, and the memtable is retried by the next flush.
+    // The value log head moves to the last entry flushed, along with the table in manifest.
+    fn flush_memtables(&self) -> Result<(), Error> {
+        loop {
+            let mt = match self.lsm.read().unwrap().oldest_imm() {
+                Some(mt) => mt,
+                None => return Ok(()),
+            };
+            let mut builder = TableBuilder::new(DEFAULT_BLOCK_SIZE, self.cfg.bloom_bits_per_key);
+            let mut head = ValuePointer::default();
+            let mut max_version = 0;
+            for (key, vs) in mt.read().unwrap().iter() {
+                max_version = max_version.max(parse_ts(key));
+                let mut value = Vec::with_capacity(ValueStruct::SIZE as usize);
+                vs.encode(&mut value)?;
+                builder.add(key, &value);
+                let p = vs.pointer;
+                if (p.fid(), p.offset() + p.len()) > (head.fid(), head.offset() + head.len()) {
+                    head = p;
+                }
+            }
+            let t = self.create_table(builder)?;
+            self.stats.lock().unwrap().flushed_bytes += t.size();
+            self.manifest.lock().unwrap().add_changes(&[
+                ManifestChange::AddTable {
+                    level: 0,
+                    id: t.id(),
+                    smallest: t.smallest().to_vec(),
+                    biggest: t.biggest().to_vec(),
+                },
+                ManifestChange::SetHead(head),
+                ManifestChange::SetNextFileId(self.next_table_id.load(Ordering::SeqCst)),
+                ManifestChange::SetMaxVersion(max_version),
+            ])?;
+            // the table is added before the memtable is dropped, so readers never miss the keys.
+            self.levels.write().unwrap()[0].add_table(t);
+            self.lsm.write().unwrap().remove_oldest_imm();
+            self.bg_cond.notify_all();
+            self.vlog.read().unwrap().set_head(head)?;
+        }
+    }
+
+    fn set_bg_err(&self, e: Error) {
+        *self.bg_err.lock().unwrap() = Some(e);
+        self.bg_cond.notify_all();
+    }
+
+    // Write a table file with a new id, it's added to manifest by the caller.
+    fn create_table(&self, builder: TableBuilder) -> Result<Arc<Table>, Error> {
+        let id = self.next_table_id.fetch_add(1, Ordering::SeqCst);
+        let cache = Some(Arc::clone(&self.block_cache));
+        let t = Table::create(
+            &self.cfg.dir,
+            id,
+            &builder.finish(),
+            self.cfg.table_loading_mode,
+            cache,
+        )?;
+        Ok(Arc::new(t))
+    }
+}
+
+// Impl write stalls
+impl Core {
+    // Delay the write a little if level 0 piles up, and block it once level 0 or frozen memtables
+    // reach the stop thresholds, until background jobs catch up or fail.
+    // It's called before commits take the write lock of the oracle.
+    fn stall_writes(self: &Arc<Self>) -> Result<(), Error> {
+        let mut bg_err = self.bg_err.lock().unwrap();
+        let mut stopped = false;
+        loop {
+            if let Some(e) = bg_err.take() {
+                return Err(e);
+            }
+            let l0_tables = self.levels.read().unwrap()[0].tables().len();
+            let imm_count = self.lsm.read().unwrap().imm_count();
+            if l0_tables >= self.cfg.level0_stop_tables || imm_count >= self.cfg.max_imm_memtables {
+                if !stopped {
+                    stopped = true;
+                    self.stats.lock().unwrap().write_stops += 1;
+                    // in case the last job failed, jobs with nothing to do return at once.
+                    self.schedule_flush();
+                    self.schedule_compaction();
+                }
+                bg_err = self.bg_cond
+                    .wait_timeout(bg_err, Duration::from_millis(10))
+                    .unwrap()
+                    .0;
+                continue;
+            }
+            if !stopped && l0_tables >= self.cfg.level0_slowdown_tables {
+                self.stats.lock().unwrap().write_slowdowns += 1;
+                drop(bg_err);
+                thread::sleep(Duration::from_millis(1));
+            }
+            return Ok(());
+        }
+    }
 }
 
-pub struct DB {}
+impl Drop for DB {
+    fn drop(&mut self) {
+        // flushes schedule compactions, so they are waited first.
+        self.core.flush_pool.join();
+        self.core.compact_pool.join();
+        // prefetch jobs hold the value log, let them finish before it's closed.
+        self.core.prefetch_pool.join();
+    }
+}
 
+// Impl value log gc
 impl DB {
-    fn open(cfg: &Config) {
-        let meta = fs::metadata(&cfg.dir);
-        fs::create_dir_all(&cfg.dir).unwrap();
-        fs::create_dir_all(&cfg.value_dir).unwrap();
+    /// Rewrite a value log file if at least `discard_ratio` of it is stale,
+    /// returns whether a file is rewritten.
+    /// Only files whose entries are all persisted in tables are considered.
+    pub fn run_value_log_gc(&self, discard_ratio: f64) -> Result<bool, Error> {
+        let discard_ts = self.core.orc.discard_ts();
+        let mut vlog = self.core.vlog.write().unwrap();
+        let collected = vlog.run_gc(
+            discard_ratio,
+            |v, p| self.gc_action(v, p, discard_ts),
+            |values, pointers| {
+                let mut lsm = self.core.lsm.write().unwrap();
+                for (v, p) in values.iter().zip(pointers) {
+                    let ts = parse_ts(&v.key);
+                    lsm.write(parse_key(&v.key), ts, ValueStruct::new(v.meta, *p));
+                }
+                self.core.freeze_if_full(&mut lsm);
+                Ok(())
+            },
+        )?;
+        if vlog.has_pending_deletes() && self.core.vlog_readers.load(Ordering::SeqCst) == 0 {
+            vlog.delete_pending_segments()?;
+        }
+        Ok(collected)
+    }
+
+    // An entry in value log is live if the LSM tree still points at it,
+    // and the version may still be read by some transaction.
+    // Tombstones are never read from value log once they are in tables.
+    // Only the newest version of a key is rewritten, an older one would be put into memtable
+    // above the newer version, which breaks reads stopping at the first version found.
+    fn gc_action(&self, v: &Value, p: &ValuePointer, discard_ts: u64) -> Result<GcAction, Error> {
+        if v.is_deleted() {
+            return Ok(GcAction::Discard);
+        }
+        let (key, ts) = (parse_key(&v.key), parse_ts(&v.key));
+        let newest_ts = match self.get_value_struct(key, u64::MAX)? {
+            Some((newest_ts, ref vs)) if newest_ts == ts && vs.pointer == *p => {
+                return Ok(GcAction::Rewrite)
+            }
+            Some((newest_ts, _)) => newest_ts,
+            None => return Ok(GcAction::Discard),
+        };
+        // overwritten by a newer version that every transaction can see.
+        if newest_ts <= discard_ts {
+            return Ok(GcAction::Discard);
+        }
+        match self.get_value_struct(key, ts)? {
+            Some((version, vs)) if version == ts && vs.pointer == *p => Ok(GcAction::Keep),
+            _ => Ok(GcAction::Discard),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn test_config(dir: &Path) -> Config {
+        ConfigBuilder::new(dir.join("lsm"))
+            .value_dir(dir.join("vlog"))
+            .build()
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_open() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = test_config(tmp_dir.path());
+        let db = DB::open(&cfg);
+        assert!(db.is_ok(), "{:?}", db.err());
+        assert!(cfg.dir().is_dir());
+        assert!(cfg.value_dir().is_dir());
+        assert_eq!(MAX_LEVELS as usize, db.unwrap().core.levels.read().unwrap().len());
+    }
+
+    #[test]
+    fn test_open_with_invalid_config() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path())
+            .value_dir(tmp_dir.path().join("vlog"))
+            .build();
+        assert!(DB::open(&cfg).is_err());
+        assert!(!tmp_dir.path().join("vlog").exists());
+    }
+
+    #[test]
+    fn test_set_get_delete() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        assert_eq!(None, db.get(b"key1").unwrap());
+
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        assert_eq!(Some(b"value1".to_vec()), db.get(b"key1").unwrap());
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key2").unwrap());
+
+        // overwrite
+        db.set(b"key1", b"value3").unwrap();
+        assert_eq!(Some(b"value3".to_vec()), db.get(b"key1").unwrap());
+
+        db.delete(b"key1").unwrap();
+        assert_eq!(None, db.get(b"key1").unwrap());
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key2").unwrap());
+    }
+
+    #[test]
+    fn test_get_with_ts() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        let ts = db.begin(true).read_ts();
+        db.set(b"key1", b"value2").unwrap();
+        assert_eq!(None, db.get_with_ts(b"key1", ts - 1).unwrap());
+        assert_eq!(Some(b"value1".to_vec()), db.get_with_ts(b"key1", ts).unwrap());
+        assert_eq!(Some(b"value2".to_vec()), db.get_with_ts(b"key1", ts + 1).unwrap());
+    }
+
+    #[test]
+    fn test_delete_with_ts() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        let ts = db.begin(true).read_ts();
+        db.delete(b"key1").unwrap();
+        assert_eq!(None, db.get(b"key1").unwrap());
+        // older version is still readable.
+        assert_eq!(Some(b"value1".to_vec()), db.get_with_ts(b"key1", ts).unwrap());
+        db.set(b"key1", b"value2").unwrap();
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+        assert_eq!(None, db.get_with_ts(b"key1", ts + 1).unwrap());
+    }
+
+    #[test]
+    fn test_replay_on_open() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = test_config(tmp_dir.path());
+        let ts = {
+            let db = DB::open(&cfg).unwrap();
+            db.set(b"key1", b"value1").unwrap();
+            db.set(b"key2", b"value2").unwrap();
+            db.delete(b"key2").unwrap();
+            let mut txn = db.begin(false);
+            txn.set(b"key3", b"value3").unwrap();
+            txn.set(b"key4", b"value4").unwrap();
+            txn.commit().unwrap();
+            let read_ts = db.begin(true).read_ts();
+            read_ts
+        };
+
+        let db = DB::open(&cfg).unwrap();
+        assert_eq!(ts, db.begin(true).read_ts());
+        assert_eq!(Some(b"value1".to_vec()), db.get(b"key1").unwrap());
+        assert_eq!(None, db.get(b"key2").unwrap());
+        assert_eq!(Some(b"value2".to_vec()), db.get_with_ts(b"key2", 2).unwrap());
+        assert_eq!(Some(b"value3".to_vec()), db.get(b"key3").unwrap());
+        assert_eq!(Some(b"value4".to_vec()), db.get(b"key4").unwrap());
+    }
+
+    #[test]
+    fn test_replay_torn_txn() {
+        use std::fs::OpenOptions;
+
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = test_config(tmp_dir.path());
+        let log_path = cfg.value_dir().join("000000.vlog");
+        let size = {
+            let db = DB::open(&cfg).unwrap();
+            db.set(b"key1", b"value1").unwrap();
+            let size = fs::metadata(&log_path).unwrap().len();
+            let mut txn = db.begin(false);
+            txn.set(b"key2", b"value2").unwrap();
+            txn.set(b"key3", b"value3").unwrap();
+            txn.commit().unwrap();
+            size
+        };
+        // crash in the middle of writing the second transaction.
+        let full_size = fs::metadata(&log_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(full_size - 3)
+            .unwrap();
+
+        {
+            let db = DB::open(&cfg).unwrap();
+            assert_eq!(size, fs::metadata(&log_path).unwrap().len());
+            assert_eq!(Some(b"value1".to_vec()), db.get(b"key1").unwrap());
+            assert_eq!(None, db.get(b"key2").unwrap());
+            assert_eq!(None, db.get(b"key3").unwrap());
+            db.set(b"key4", b"value4").unwrap();
+        }
+
+        let db = DB::open(&cfg).unwrap();
+        assert_eq!(Some(b"value1".to_vec()), db.get(b"key1").unwrap());
+        assert_eq!(Some(b"value4".to_vec()), db.get(b"key4").unwrap());
+    }
+
+    #[test]
+    fn test_open_with_corrupted_entry() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = test_config(tmp_dir.path());
+        let pointer = {
+            let db = DB::open(&cfg).unwrap();
+            db.set(b"key1", b"value1").unwrap();
+            db.set(b"key2", b"value2").unwrap();
+            db.set(b"key3", b"value3").unwrap();
+            db.get_value_struct(b"key2", u64::MAX).unwrap().unwrap().1.pointer
+        };
+        // flip a bit in the value of key2, transactions after it must not be dropped silently.
+        let log_path = cfg.value_dir().join("000000.vlog");
+        let mut buf = fs::read(&log_path).unwrap();
+        buf[(pointer.offset() + pointer.len()) as usize - 5] ^= 1;
+        fs::write(&log_path, &buf).unwrap();
+
+        assert!(DB::open(&cfg).is_err());
+        assert_eq!(buf.len() as u64, fs::metadata(&log_path).unwrap().len());
+    }
+
+    // Open a db whose value log rolls over after each write,
+    // and move head of value log forward as if all entries are in tables.
+    fn open_gc_db(dir: &Path) -> DB {
+        let cfg = ConfigBuilder::new(dir.join("lsm"))
+            .value_dir(dir.join("vlog"))
+            .value_log_file_size(64)
+            .build();
+        DB::open(&cfg).unwrap()
+    }
+
+    fn move_head(db: &DB) {
+        let vlog = db.core.vlog.read().unwrap();
+        let fid = vlog.active_segment().unwrap().fid();
+        vlog.set_head(ValuePointer::new(fid, 0, 0)).unwrap();
+    }
+
+    #[test]
+    fn test_value_log_gc() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = open_gc_db(tmp_dir.path());
+        let mut txn = db.begin(false);
+        txn.set(b"key1", b"value1").unwrap();
+        txn.set(b"key2", b"value2").unwrap();
+        txn.set(b"key3", b"value3").unwrap();
+        txn.commit().unwrap();
+        db.set(b"key1", b"value4").unwrap();
+        db.delete(b"key2").unwrap();
+        db.set(b"key4", b"value5").unwrap();
+        move_head(&db);
+
+        let first_log = tmp_dir.path().join("vlog").join("000000.vlog");
+        assert!(first_log.exists());
+        // only key3 is live in the first file.
+        assert!(!db.run_value_log_gc(0.9).unwrap());
+        assert!(db.run_value_log_gc(0.5).unwrap());
+        assert!(!first_log.exists());
+
+        assert_eq!(Some(b"value4".to_vec()), db.get(b"key1").unwrap());
+        assert_eq!(None, db.get(b"key2").unwrap());
+        assert_eq!(Some(b"value3".to_vec()), db.get(b"key3").unwrap());
+        assert_eq!(Some(b"value5".to_vec()), db.get(b"key4").unwrap());
+    }
+
+    #[test]
+    fn test_value_log_gc_keep_visible_versions() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = open_gc_db(tmp_dir.path());
+        db.set(b"key1", b"value1").unwrap();
+        let mut txn = db.begin(true);
+        db.set(b"key1", b"value2").unwrap();
+        db.set(b"key2", b"value3").unwrap();
+        move_head(&db);
+
+        // the old version is still visible to `txn`, so the file is kept.
+        let first_log = tmp_dir.path().join("vlog").join("000000.vlog");
+        assert!(!db.run_value_log_gc(0.5).unwrap());
+        assert!(first_log.exists());
+        assert_eq!(Some(b"value1".to_vec()), txn.get(b"key1").unwrap());
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+        txn.discard();
+        assert!(db.run_value_log_gc(0.5).unwrap());
+        assert!(!first_log.exists());
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+    }
+
+    #[test]
+    fn test_value_log_gc_newer_version_in_table() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = open_gc_db(tmp_dir.path());
+        db.set(b"key1", b"value1").unwrap();
+        let mut txn = db.begin(true);
+        db.set(b"key1", b"value2").unwrap();
+        for i in 0..10 {
+            db.set(format!("key{}", i + 2).as_bytes(), b"filler").unwrap();
+        }
+        // the newer version is in a table, and the head passes all segments but the active one.
+        db.flatten().unwrap();
+        assert!(db.core.lsm.read().unwrap().is_mt_empty());
+
+        db.run_value_log_gc(0.01).unwrap();
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+        assert_eq!(Some(b"value1".to_vec()), txn.get(b"key1").unwrap());
+        let kvs = collect(&mut db.iter());
+        assert_eq!((b"key1".to_vec(), b"value2".to_vec()), kvs[0]);
+
+        // still the newer version once the memtable is flushed.
+        db.flatten().unwrap();
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+    }
+
+    #[test]
+    fn test_value_log_gc_delete_after_reads() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = open_gc_db(tmp_dir.path());
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        db.set(b"key1", b"value3").unwrap();
+        move_head(&db);
+
+        let first_log = tmp_dir.path().join("vlog").join("000000.vlog");
+        // a reader which may still hold pointers into the first file.
+        db.core.vlog_readers.fetch_add(1, Ordering::SeqCst);
+        assert!(db.run_value_log_gc(0.5).unwrap());
+        assert!(first_log.exists());
+        db.done_vlog_read();
+        assert!(!first_log.exists());
+        assert_eq!(Some(b"value3".to_vec()), db.get(b"key1").unwrap());
+    }
+
+    #[test]
+    fn test_discard_stats_on_overwrite() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        assert!(db.core.vlog.read().unwrap().ranked_discard_stats().is_empty());
+
+        let old = db.get_value_struct(b"key1", u64::MAX).unwrap().unwrap().1;
+        db.set(b"key1", b"value3").unwrap();
+        let new = db.get_value_struct(b"key1", u64::MAX).unwrap().unwrap().1;
+        db.delete(b"key1").unwrap();
+        let discarded = u64::from(old.pointer.len() + new.pointer.len());
+        assert_eq!(
+            vec![(0, discarded)],
+            db.core.vlog.read().unwrap().ranked_discard_stats()
+        );
+    }
+
+    fn collect(it: &mut DBIterator) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut kvs = vec![];
+        while it.valid() {
+            kvs.push((it.key().to_vec(), it.value().unwrap()));
+            it.next();
+        }
+        assert!(it.err().is_none());
+        kvs
+    }
+
+    fn kv(k: &[u8], v: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        (k.to_vec(), v.to_vec())
+    }
+
+    #[test]
+    fn test_iter() {
+        use table::tests::build_table;
+
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let mut db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        assert!(!db.iter().valid());
+
+        // key2 and key4 are in a table.
+        let pointers = db.core.vlog
+            .write()
+            .unwrap()
+            .write(&[
+                Value::new(&key_with_ts(b"key2", 1), b"old2"),
+                Value::new(&key_with_ts(b"key4", 1), b"old4"),
+            ])
+            .unwrap();
+        let entry = |k: &[u8], p: ValuePointer| {
+            let mut buf = vec![];
+            ValueStruct::new(0, p).encode(&mut buf).unwrap();
+            (key_with_ts(k, 1), buf)
+        };
+        db.core.levels.write().unwrap()[1].add_table(build_table(&[vec![
+            entry(b"key2", pointers[0]),
+            entry(b"key4", pointers[1]),
+        ]]));
+        Arc::get_mut(&mut db.core).unwrap().orc = Oracle::new(2);
+
+        db.set(b"key3", b"value3").unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        db.delete(b"key4").unwrap();
+        db.set(b"key5", b"value5").unwrap();
+        db.delete(b"key5").unwrap();
+
+        let mut it = db.iter();
+        assert_eq!(
+            vec![
+                kv(b"key1", b"value1"),
+                kv(b"key2", b"value2"),
+                kv(b"key3", b"value3"),
+            ],
+            collect(&mut it)
+        );
+        it.seek(b"key2");
+        assert_eq!(b"key2", it.key());
+        it.seek(b"key21");
+        assert_eq!(b"key3", it.key());
+        it.seek(b"key4");
+        assert!(!it.valid());
+        it.rewind();
+        assert_eq!(b"key1", it.key());
+    }
+
+    #[test]
+    fn test_iter_snapshot() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+
+        let mut it = db.iter();
+        db.set(b"key1", b"value3").unwrap();
+        db.delete(b"key2").unwrap();
+        db.set(b"key3", b"value4").unwrap();
+        assert_eq!(
+            vec![kv(b"key1", b"value1"), kv(b"key2", b"value2")],
+            collect(&mut it)
+        );
+        assert_eq!(
+            vec![kv(b"key1", b"value3"), kv(b"key3", b"value4")],
+            collect(&mut db.iter())
+        );
+    }
+
+    #[test]
+    fn test_range_and_prefix() {
+        use std::collections::Bound;
+        use table::tests::build_table;
+
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let mut db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        let pointers = db.core.vlog
+            .write()
+            .unwrap()
+            .write(&[
+                Value::new(&key_with_ts(b"b", 1), b"b"),
+                Value::new(&key_with_ts(b"ba", 1), b"ba"),
+            ])
+            .unwrap();
+        let entry = |k: &[u8], p: ValuePointer| {
+            let mut buf = vec![];
+            ValueStruct::new(0, p).encode(&mut buf).unwrap();
+            (key_with_ts(k, 1), buf)
+        };
+        db.core.levels.write().unwrap()[1].add_table(build_table(&[
+            vec![entry(b"b", pointers[0])],
+            vec![entry(b"ba", pointers[1])],
+        ]));
+        Arc::get_mut(&mut db.core).unwrap().orc = Oracle::new(2);
+        for k in [&b"a"[..], b"bb", b"b\xff", b"c", b"ca"].iter() {
+            db.set(k, k).unwrap();
+        }
+
+        let keys = |mut it: DBIterator| {
+            let mut keys = vec![];
+            while it.valid() {
+                assert_eq!(it.key(), &it.value().unwrap()[..]);
+                keys.push(it.key().to_vec());
+                it.next();
+            }
+            keys
+        };
+        let b = |k: &[u8]| k.to_vec();
+        assert_eq!(
+            vec![b(b"b"), b(b"ba"), b(b"bb"), b(b"b\xff")],
+            keys(db.range(&b"b"[..]..&b"c"[..]))
+        );
+        assert_eq!(
+            vec![b(b"ba"), b(b"bb"), b(b"b\xff"), b(b"c")],
+            keys(db.range::<&[u8], _>((
+                Bound::Excluded(&b"b"[..]),
+                Bound::Included(&b"c"[..]),
+            )))
+        );
+        assert_eq!(
+            vec![b(b"b\xff"), b(b"c"), b(b"ca")],
+            keys(db.range(&b"bz"[..]..))
+        );
+        assert_eq!(vec![b(b"a"), b(b"b")], keys(db.range(..=&b"b"[..])));
+        assert!(keys(db.range(&b"x"[..]..)).is_empty());
+
+        assert_eq!(
+            vec![b(b"b"), b(b"ba"), b(b"bb"), b(b"b\xff")],
+            keys(db.prefix(b"b"))
+        );
+        assert_eq!(vec![b(b"ba")], keys(db.prefix(b"ba")));
+        assert_eq!(keys(db.iter()), keys(db.prefix(b"")));
+
+        // seek is limited to the range.
+        let mut it = db.prefix(b"b");
+        it.seek(b"a");
+        assert_eq!(b"b", it.key());
+        it.seek(b"bc");
+        assert_eq!(b"b\xff", it.key());
+        it.seek(b"c");
+        assert!(!it.valid());
+    }
+
+    #[test]
+    fn test_iter_lazy_values() {
+        use std::fs::OpenOptions;
+        use std::io::{Seek, SeekFrom, Write};
+
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = test_config(tmp_dir.path());
+        let db = DB::open(&cfg).unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i).into_bytes();
+            db.set(&key, &key).unwrap();
+        }
+        let keys = |it: &mut DBIterator| {
+            let mut keys = vec![];
+            while it.valid() {
+                keys.push(it.key().to_vec());
+                it.next();
+            }
+            assert!(it.err().is_none());
+            keys
+        };
+        assert_eq!(10, keys(&mut db.iter()).len());
+
+        let mut it = db.iter().prefetch(3);
+        assert_eq!(
+            (0..10)
+                .map(|i| kv(format!("key{}", i).as_bytes(), format!("key{}", i).as_bytes()))
+                .collect::<Vec<_>>(),
+            collect(&mut it)
+        );
+        it.seek(b"key8");
+        assert_eq!(vec![kv(b"key8", b"key8"), kv(b"key9", b"key9")], collect(&mut it));
+
+        // values are not read by a key only scan, so it doesn't see the corruption.
+        let mut f = OpenOptions::new()
+            .write(true)
+            .open(cfg.value_dir().join("000000.vlog"))
+            .unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.write_all(&[0xff; 512]).unwrap();
+        f.sync_all().unwrap();
+        assert_eq!(10, keys(&mut db.iter()).len());
+        let mut it = db.iter();
+        let version = it.item().version();
+        assert!(it.value().is_err());
+        it.next();
+        assert_eq!(b"key1", it.key());
+        assert_eq!(version + 1, it.item().version());
+    }
+
+    #[test]
+    fn test_flush_memtable() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path().join("lsm"))
+            .value_dir(tmp_dir.path().join("vlog"))
+            .max_table_size(4096)
+            .build();
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+        let table_ids = |db: &DB| -> Vec<u64> {
+            let mut ids: Vec<u64> = db.core.levels
+                .read()
+                .unwrap()
+                .iter()
+                .flat_map(|l| l.tables().iter().map(|t| t.id()))
+                .collect();
+            ids.sort();
+            ids
+        };
+        let ids = {
+            let db = DB::open(&cfg).unwrap();
+            for i in 0..200 {
+                db.set(&key(i), &key(i)).unwrap();
+            }
+            db.delete(&key(0)).unwrap();
+            db.core.flush_pool.join();
+            db.core.compact_pool.join();
+            assert_eq!(0, db.core.lsm.read().unwrap().imm_count());
+            assert!(table_ids(&db).len() > 1);
+
+            assert_eq!(None, db.get(&key(0)).unwrap());
+            for i in 1..200 {
+                assert_eq!(Some(key(i)), db.get(&key(i)).unwrap());
+            }
+            let kvs = collect(&mut db.iter());
+            assert_eq!(199, kvs.len());
+            assert_eq!(kv(&key(1), &key(1)), kvs[0]);
+            table_ids(&db)
+        };
+
+        // tables are loaded from manifest, only entries after the head are replayed.
+        let orphan = table::table_path(cfg.dir(), 1000);
+        fs::write(&orphan, b"").unwrap();
+        let db = DB::open(&cfg).unwrap();
+        assert!(!orphan.exists());
+        assert_eq!(ids, table_ids(&db));
+        assert_eq!(0, db.core.lsm.read().unwrap().imm_count());
+        assert_eq!(None, db.get(&key(0)).unwrap());
+        for i in 1..200 {
+            assert_eq!(Some(key(i)), db.get(&key(i)).unwrap());
+        }
+        db.set(&key(0), b"new").unwrap();
+        assert_eq!(200, collect(&mut db.iter()).len());
+        let next_id = *ids.last().unwrap() + 1;
+        assert_eq!(next_id, db.core.next_table_id.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_reopen_with_all_flushed() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = test_config(tmp_dir.path());
+        let ts = {
+            let db = DB::open(&cfg).unwrap();
+            db.set(b"key1", b"value1").unwrap();
+            db.set(b"key2", b"value2").unwrap();
+            db.core.lsm.write().unwrap().freeze();
+            db.core.schedule_flush();
+            db.core.flush_pool.join();
+            let read_ts = db.begin(true).read_ts();
+            read_ts
+        };
+
+        // versions in tables are still visible without any entry to replay.
+        let db = DB::open(&cfg).unwrap();
+        assert_eq!(0, db.core.lsm.read().unwrap().imm_count());
+        assert_eq!(ts, db.begin(true).read_ts());
+        assert_eq!(Some(b"value1".to_vec()), db.get(b"key1").unwrap());
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key2").unwrap());
+    }
+
+    #[test]
+    fn test_tombstone_in_level() {
+        use table::tests::build_table;
+        use values::BIT_DELETE;
+
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let db = DB::open(&test_config(tmp_dir.path())).unwrap();
+        let pointers = db.core.vlog
+            .write()
+            .unwrap()
+            .write(&[
+                Value::new(&key_with_ts(b"key1", 1), b"value1"),
+                Value::tombstone(&key_with_ts(b"key1", 2)),
+            ])
+            .unwrap();
+        let entry = |ts: u64, vs: ValueStruct| {
+            let mut buf = vec![];
+            vs.encode(&mut buf).unwrap();
+            vec![(key_with_ts(b"key1", ts), buf)]
+        };
+        db.core.levels.write().unwrap()[2]
+            .add_table(build_table(&[entry(1, ValueStruct::new(0, pointers[0]))]));
+        assert_eq!(Some(b"value1".to_vec()), db.get_with_ts(b"key1", 2).unwrap());
+
+        // the tombstone hides the value in lower level.
+        db.core.levels.write().unwrap()[1].add_table(build_table(&[
+            entry(2, ValueStruct::new(BIT_DELETE, pointers[1])),
+        ]));
+        assert_eq!(None, db.get_with_ts(b"key1", 2).unwrap());
+        assert_eq!(Some(b"value1".to_vec()), db.get_with_ts(b"key1", 1).unwrap());
+    }
+
+    #[test]
+    fn test_write_stalls() {
+        use std::sync::mpsc;
+        use std::time::Instant;
+
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path().join("lsm"))
+            .value_dir(tmp_dir.path().join("vlog"))
+            .max_table_size(1024)
+            .level0_slowdown_tables(5)
+            .level0_stop_tables(6)
+            .max_imm_memtables(2)
+            .build();
+        let db = DB::open(&cfg).unwrap();
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+        let l0_tables = || db.core.levels.read().unwrap()[0].tables().len();
+        // hold compactions until writes are stopped.
+        let (tx, rx) = mpsc::channel::<()>();
+        db.core.compact_pool.execute(move || {
+            let _ = rx.recv();
+        });
+
+        let mut i = 0;
+        while l0_tables() < 5 {
+            db.set(&key(i), &key(i)).unwrap();
+            db.core.flush_pool.join();
+            i += 1;
+        }
+        assert_eq!(0, db.stats().write_slowdowns);
+        db.set(&key(i), &key(i)).unwrap();
+        assert_eq!(1, db.stats().write_slowdowns);
+        assert_eq!(0, db.stats().write_stops);
+
+        thread::scope(|s| {
+            let writer = s.spawn(|| {
+                for j in i + 1..i + 500 {
+                    db.set(&key(j), &key(j)).unwrap();
+                }
+            });
+            let start = Instant::now();
+            while db.stats().write_stops == 0 {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert!(!writer.is_finished());
+            assert!(l0_tables() <= 6 + 2);
+            assert!(db.core.lsm.read().unwrap().imm_count() <= 2);
+            drop(tx);
+            writer.join().unwrap();
+        });
+        for j in 0..i + 500 {
+            assert_eq!(Some(key(j)), db.get(&key(j)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_file_io_tables() {
+        use table::TableLoadMode;
+
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path().join("lsm"))
+            .value_dir(tmp_dir.path().join("vlog"))
+            .max_table_size(4096)
+            .table_loading_mode(TableLoadMode::FileIO)
+            .block_cache_size(1 << 20)
+            .build();
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+        {
+            let db = DB::open(&cfg).unwrap();
+            for i in 0..200 {
+                db.set(&key(i), &key(i)).unwrap();
+            }
+            db.flatten().unwrap();
+            assert!(db.core.block_cache.usage() > 0);
+            for i in 0..200 {
+                assert_eq!(Some(key(i)), db.get(&key(i)).unwrap());
+            }
+        }
+        let db = DB::open(&cfg).unwrap();
+        for i in 0..200 {
+            assert_eq!(Some(key(i)), db.get(&key(i)).unwrap(), "{}", i);
+        }
+        assert_eq!(200, collect(&mut db.iter()).len());
+        assert!(db.core.block_cache.usage() > 0);
+    }
+
+    #[test]
+    fn test_parallel_reads() {
+        let tmp_dir = tempdir::TempDir::new("db").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path().join("lsm"))
+            .value_dir(tmp_dir.path().join("vlog"))
+            .value_log_file_size(1024)
+            .build();
+        let db = DB::open(&cfg).unwrap();
+        let key = |i: usize| format!("key{:05}", i).into_bytes();
+        for i in 0..200 {
+            db.set(&key(i), &key(i)).unwrap();
+        }
+        // readers resolve pointers into many segments while a writer appends.
+        thread::scope(|s| {
+            s.spawn(|| {
+                for i in 200..400 {
+                    db.set(&key(i), &key(i)).unwrap();
+                }
+            });
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..200 {
+                        assert_eq!(Some(key(i)), db.get(&key(i)).unwrap());
+                    }
+                });
+            }
+        });
+        for i in 0..400 {
+            assert_eq!(Some(key(i)), db.get(&key(i)).unwrap());
+        }
+    }
 }
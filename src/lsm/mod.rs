@@ -1,94 +1,178 @@
-extern crate bincode;
-extern crate serde;
 extern crate skiplist;
 
-use failure;
-use self::bincode::config;
-use self::header::*;
-use self::serde::Serialize;
 use self::skiplist::SkipMap;
-use std;
-use std::io::Write;
+use keys::{compare_keys, key_with_ts, parse_ts, same_key};
+use std::cmp::Ordering;
+use std::collections::Bound;
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
 
-mod header;
+/// Key with timestamp in memtable, ordered by `keys::compare_keys`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Key(Vec<u8>);
 
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        compare_keys(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Deref for Key {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
 
-type Key = Vec<u8>;
 
+// Approximate bytes of a skiplist node besides the key and the value.
+const NODE_OVERHEAD: usize = 64;
 
+/// A sorted map of keys with timestamps, it's frozen once full and flushed to a table.
+pub struct MemTable<V> {
+    map: SkipMap<Key, V>,
+    // approximate bytes used by entries.
+    size: usize,
+}
+
+impl<V> MemTable<V> {
+    fn new() -> MemTable<V> {
+        MemTable {
+            map: SkipMap::with_capacity(1024 * 1024),
+            size: 0,
+        }
+    }
+
+    fn write(&mut self, k: &[u8], ts: u64, v: V) -> Option<V> {
+        let key = Key(key_with_ts(k, ts));
+        let size = key.len() + mem::size_of::<V>() + NODE_OVERHEAD;
+        let old = self.map.insert(key, v);
+        if old.is_none() {
+            self.size += size;
+        }
+        old
+    }
+
+    /// Get the newest version of `k` whose timestamp is not greater than `read_ts`.
+    pub fn get(&self, k: &[u8], read_ts: u64) -> Option<(u64, &V)> {
+        let seek_key = Key(key_with_ts(k, read_ts));
+        self.map
+            .range(Bound::Included(&seek_key), Bound::Unbounded)
+            .next()
+            .filter(|&(key, _)| same_key(key, &seek_key))
+            .map(|(key, v)| (parse_ts(key), v))
+    }
+
+    /// Get the first entry from `start` in the order of `keys::compare_keys`,
+    /// returns the key with timestamp and the value.
+    pub fn first_from(&self, start: Bound<&[u8]>) -> Option<(&[u8], &V)> {
+        let start = match start {
+            Bound::Included(k) => Bound::Included(Key(k.to_vec())),
+            Bound::Excluded(k) => Bound::Excluded(Key(k.to_vec())),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.map
+            .range(start.as_ref(), Bound::Unbounded)
+            .next()
+            .map(|(key, v)| (&key.0[..], v))
+    }
+
+    /// Iterate all entries in the order of `keys::compare_keys`.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &V)> {
+        self.map.iter().map(|(key, v)| (&key.0[..], v))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Approximate bytes used by entries.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// A memtable shared with iterators and the flush job.
+pub type SharedMemTable<V> = Arc<RwLock<MemTable<V>>>;
+
+/// The mutable memtable and the frozen ones waiting to be flushed.
+#[allow(clippy::upper_case_acronyms)]
 pub struct LSM<V> {
-    mt: SkipMap<Key, V>,
+    mt: SharedMemTable<V>,
+    // frozen memtables from old to new.
+    imm: Vec<SharedMemTable<V>>,
+    max_size: usize,
 }
 
-impl<V> LSM<V> where {
+impl<V: Clone> LSM<V> {
+    /// Create an LSM whose memtable is frozen once it uses about `max_size` bytes.
     pub fn new(max_size: u32) -> LSM<V> {
-        let mt = SkipMap::with_capacity(1024 * 1024);
         LSM {
-            mt
+            mt: Arc::new(RwLock::new(MemTable::new())),
+            imm: vec![],
+            max_size: max_size as usize,
         }
     }
 
-    pub fn write(&mut self, k: Key, v: V) -> Option<V> {
-        self.mt.insert(k, v)
-    }
-
-    pub fn get(&self, k: &Key) -> Option<&V> {
-        self.mt.get(k)
-    }
-
-    fn flush_mt<W>(mt: SkipMap<Key, V>, mut w: W) -> std::result::Result<(), failure::Error>
-        where V: Serialize, W: Write {
-        let mut config = config();
-        config.big_endian().no_limit();
-
-        let restart_interval = 100u32; // number of kv in each blocks.
-
-        // buf for a single skip map
-        // TODO: init with capacity.
-        let mut buf: Vec<u8> = vec![];
-        // split the ordered skip list into blocks,
-        // keys in each block share a same key prefix to reduce space.
-        let mut base_key: Vec<u8> = vec![]; // the same key prefix for current block
-        let mut base_offset = 0u32; // offset in the file for the current block
-        let mut restarts: Vec<u32> = vec![]; // base offset for each block
-        let mut prev_offset: Option<u32> = None; // track offset for previous kv pair. Offset is relative to block based offset.
-        // number of kv written for the current block, init to restart_interval to fake restart for the first kv
-        let mut counter = restart_interval;
-        let mut restart = true;
-        for (ref k, ref v) in mt.into_iter() {
-            if (counter >= restart_interval) {
-                counter = 0;
-                base_key = k.clone();
-                base_offset = buf.len() as u32;
-                restarts.push(base_offset);
-                prev_offset = None; // should set prev_offset to prev block's last kv
-            }
-
-
-            let lcp = lcp(&k, &base_key);
-            let diff_k = &k[lcp.len()..];
-            let vlen = config.serialized_size(v)? as u32;
-            let header = Header {
-                plen: base_key.len() as u16,
-                klen: diff_k.len() as u16,
-                vlen: vlen,
-                prev: prev_offset,
-            };
-            config.serialize_into(&mut buf, &header)?;
-            config.serialize_into(&mut buf, diff_k)?;
-            config.serialize_into(&mut buf, v)?;
-
-            counter += 1;
-        }
+    pub fn write(&mut self, k: &[u8], ts: u64, v: V) -> Option<V> {
+        self.mt.write().unwrap().write(k, ts, v)
+    }
+
+    /// Get the newest version of `k` whose timestamp is not greater than `read_ts`,
+    /// searching from the newest memtable.
+    pub fn get(&self, k: &[u8], read_ts: u64) -> Option<(u64, V)> {
+        self.memtables().iter().find_map(|mt| {
+            let mt = mt.read().unwrap();
+            mt.get(k, read_ts).map(|(ts, v)| (ts, v.clone()))
+        })
+    }
+
+    /// Whether the memtable should be frozen.
+    pub fn is_full(&self) -> bool {
+        self.mt.read().unwrap().size() >= self.max_size
+    }
+
+    /// Whether nothing is written since the last freeze.
+    pub fn is_mt_empty(&self) -> bool {
+        self.mt.read().unwrap().is_empty()
+    }
+
+    /// Freeze the memtable and start a new one.
+    pub fn freeze(&mut self) {
+        let mt = mem::replace(&mut self.mt, Arc::new(RwLock::new(MemTable::new())));
+        self.imm.push(mt);
+    }
+
+    /// All memtables from new to old.
+    pub fn memtables(&self) -> Vec<SharedMemTable<V>> {
+        let mut mts = vec![Arc::clone(&self.mt)];
+        mts.extend(self.imm.iter().rev().cloned());
+        mts
+    }
+
+    /// The oldest frozen memtable.
+    pub fn oldest_imm(&self) -> Option<SharedMemTable<V>> {
+        self.imm.first().cloned()
+    }
+
+    /// Drop the oldest frozen memtable once it's flushed.
+    pub fn remove_oldest_imm(&mut self) {
+        self.imm.remove(0);
+    }
 
-        // write buf all in one to reduce disk io.
-        w.write_all(&buf)?;
-        w.flush()?;
-        Ok(())
+    pub fn imm_count(&self) -> usize {
+        self.imm.len()
     }
 }
 
-fn lcp<'a, T>(v1: &'a [T], v2: &'a [T]) -> &'a [T] where T: PartialEq {
+pub(crate) fn lcp<'a, T>(v1: &'a [T], v2: &'a [T]) -> &'a [T] where T: PartialEq {
     let min_len = v1.len().min(v2.len());
     let mut max_len = min_len;
     for i in 0..min_len {
@@ -102,6 +186,69 @@ fn lcp<'a, T>(v1: &'a [T], v2: &'a [T]) -> &'a [T] where T: PartialEq {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    #[test]
+    fn test_versioned_get() {
+        let mut lsm = LSM::new(1024);
+        lsm.write(b"a", 1, 1);
+        lsm.write(b"a", 3, 3);
+        lsm.write(b"ab", 2, 2);
+        lsm.write(b"b", 5, 5);
+
+        assert_eq!(None, lsm.get(b"a", 0));
+        assert_eq!(Some((1, 1)), lsm.get(b"a", 1));
+        assert_eq!(Some((1, 1)), lsm.get(b"a", 2));
+        assert_eq!(Some((3, 3)), lsm.get(b"a", 3));
+        assert_eq!(Some((3, 3)), lsm.get(b"a", u64::MAX));
+        assert_eq!(None, lsm.get(b"ab", 1));
+        assert_eq!(Some((2, 2)), lsm.get(b"ab", 2));
+        assert_eq!(None, lsm.get(b"b", 4));
+        assert_eq!(None, lsm.get(b"c", u64::MAX));
+    }
+
+    #[test]
+    fn test_first_from() {
+        let mut lsm = MemTable::new();
+        lsm.write(b"a", 1, 1);
+        lsm.write(b"a", 3, 3);
+        lsm.write(b"b", 2, 2);
+
+        let a3 = key_with_ts(b"a", 3);
+        assert_eq!(Some((&a3[..], &3)), lsm.first_from(Bound::Unbounded));
+        assert_eq!(Some((&a3[..], &3)), lsm.first_from(Bound::Included(&a3)));
+        let a1 = key_with_ts(b"a", 1);
+        assert_eq!(Some((&a1[..], &1)), lsm.first_from(Bound::Excluded(&a3)));
+        let b2 = key_with_ts(b"b", 2);
+        assert_eq!(Some((&b2[..], &2)), lsm.first_from(Bound::Excluded(&a1)));
+        assert_eq!(None, lsm.first_from(Bound::Excluded(&b2)));
+    }
+
+    #[test]
+    fn test_freeze() {
+        let mut lsm = LSM::new(200);
+        lsm.write(b"a", 1, 1);
+        assert!(!lsm.is_full());
+        lsm.write(b"a", 2, 2);
+        lsm.write(b"b", 1, 1);
+        assert!(lsm.is_full());
+
+        lsm.freeze();
+        assert!(!lsm.is_full());
+        assert_eq!(1, lsm.imm_count());
+        lsm.write(b"a", 3, 3);
+        assert_eq!(Some((3, 3)), lsm.get(b"a", u64::MAX));
+        assert_eq!(Some((2, 2)), lsm.get(b"a", 2));
+        assert_eq!(Some((1, 1)), lsm.get(b"b", u64::MAX));
+        assert_eq!(2, lsm.memtables().len());
+
+        let imm = lsm.oldest_imm().unwrap();
+        assert_eq!(3, imm.read().unwrap().iter().count());
+        lsm.remove_oldest_imm();
+        assert_eq!(None, lsm.get(b"b", u64::MAX));
+        assert!(lsm.oldest_imm().is_none());
+    }
+
     #[test]
     fn test_lcp() {
         assert_eq!(b"", super::lcp(b"abc", b""));
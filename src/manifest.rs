@@ -0,0 +1,540 @@
+// the impls generated by `derive(Fail)` are non-local.
+#![allow(non_local_definitions)]
+
+extern crate crc;
+
+use self::crc::crc32;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use failure::Error;
+use std::collections::HashMap;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use values::ValuePointer;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const MAGIC: u32 = 0x5344_4d46;
+const VERSION: u32 = 1;
+// rewrite the log once it has this many deletions,
+// and they are much more than the tables left.
+const REWRITE_THRESHOLD: usize = 10_000;
+const REWRITE_RATIO: usize = 10;
+
+#[derive(Debug, Fail)]
+pub enum ManifestError {
+    #[fail(display = "Bad magic or version of manifest: magic({:#010x}) version({})", magic,
+           version)]
+    BadMagic { magic: u32, version: u32 },
+    #[fail(display = "Checksum mismatch of manifest record at offset {}", offset)]
+    ChecksumMismatch { offset: u64 },
+    #[fail(display = "Corrupted length of manifest record at offset {}", offset)]
+    CorruptedLength { offset: u64 },
+    #[fail(display = "Unknown manifest change tag {}", tag)]
+    UnknownTag { tag: u8 },
+    #[fail(display = "Table {} is added twice", id)]
+    DuplicateTable { id: u64 },
+    #[fail(display = "Table {} to delete is not at level {}", id, level)]
+    MissingTable { id: u64, level: u32 },
+}
+
+/// A change to the state of the LSM tree,
+/// changes applied together are written as one record of the manifest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ManifestChange {
+    AddTable {
+        level: u32,
+        id: u64,
+        smallest: Vec<u8>,
+        biggest: Vec<u8>,
+    },
+    DeleteTable { level: u32, id: u64 },
+    /// Entries of value log up to the head are persisted in tables.
+    SetHead(ValuePointer),
+    SetNextFileId(u64),
+    /// Versions of entries in tables are not greater than it, it never decreases.
+    SetMaxVersion(u64),
+}
+
+impl ManifestChange {
+    const ADD_TABLE: u8 = 0;
+    const DELETE_TABLE: u8 = 1;
+    const SET_HEAD: u8 = 2;
+    const SET_NEXT_FILE_ID: u8 = 3;
+    const SET_MAX_VERSION: u8 = 4;
+
+    fn encode<T: WriteBytesExt>(&self, writer: &mut T) -> io::Result<()> {
+        match *self {
+            ManifestChange::AddTable {
+                level,
+                id,
+                ref smallest,
+                ref biggest,
+            } => {
+                writer.write_u8(Self::ADD_TABLE)?;
+                writer.write_u32::<BigEndian>(level)?;
+                writer.write_u64::<BigEndian>(id)?;
+                for key in [smallest, biggest].iter() {
+                    writer.write_u32::<BigEndian>(key.len() as u32)?;
+                    writer.write_all(key)?;
+                }
+            }
+            ManifestChange::DeleteTable { level, id } => {
+                writer.write_u8(Self::DELETE_TABLE)?;
+                writer.write_u32::<BigEndian>(level)?;
+                writer.write_u64::<BigEndian>(id)?;
+            }
+            ManifestChange::SetHead(ref head) => {
+                writer.write_u8(Self::SET_HEAD)?;
+                head.encode(writer)?;
+            }
+            ManifestChange::SetNextFileId(id) => {
+                writer.write_u8(Self::SET_NEXT_FILE_ID)?;
+                writer.write_u64::<BigEndian>(id)?;
+            }
+            ManifestChange::SetMaxVersion(version) => {
+                writer.write_u8(Self::SET_MAX_VERSION)?;
+                writer.write_u64::<BigEndian>(version)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode<T: ReadBytesExt>(reader: &mut T) -> Result<ManifestChange, Error> {
+        let change = match reader.read_u8()? {
+            Self::ADD_TABLE => {
+                let level = reader.read_u32::<BigEndian>()?;
+                let id = reader.read_u64::<BigEndian>()?;
+                let mut keys = vec![];
+                for _ in 0..2 {
+                    let mut key = vec![0; reader.read_u32::<BigEndian>()? as usize];
+                    reader.read_exact(&mut key)?;
+                    keys.push(key);
+                }
+                let biggest = keys.pop().unwrap();
+                let smallest = keys.pop().unwrap();
+                ManifestChange::AddTable {
+                    level,
+                    id,
+                    smallest,
+                    biggest,
+                }
+            }
+            Self::DELETE_TABLE => ManifestChange::DeleteTable {
+                level: reader.read_u32::<BigEndian>()?,
+                id: reader.read_u64::<BigEndian>()?,
+            },
+            Self::SET_HEAD => ManifestChange::SetHead(ValuePointer::decode(reader)?),
+            Self::SET_NEXT_FILE_ID => {
+                ManifestChange::SetNextFileId(reader.read_u64::<BigEndian>()?)
+            }
+            Self::SET_MAX_VERSION => {
+                ManifestChange::SetMaxVersion(reader.read_u64::<BigEndian>()?)
+            }
+            tag => Err(ManifestError::UnknownTag { tag })?,
+        };
+        Ok(change)
+    }
+}
+
+/// A table recorded in the manifest.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TableManifest {
+    pub level: u32,
+    pub smallest: Vec<u8>,
+    pub biggest: Vec<u8>,
+}
+
+/// The state of the LSM tree built by applying changes in the manifest.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub tables: HashMap<u64, TableManifest>,
+    pub head: ValuePointer,
+    pub next_file_id: u64,
+    pub max_version: u64,
+    // tables added and deleted since the last rewrite.
+    creations: usize,
+    deletions: usize,
+}
+
+impl Manifest {
+    fn apply(&mut self, change: &ManifestChange) -> Result<(), ManifestError> {
+        match *change {
+            ManifestChange::AddTable {
+                level,
+                id,
+                ref smallest,
+                ref biggest,
+            } => {
+                if self.tables.contains_key(&id) {
+                    return Err(ManifestError::DuplicateTable { id });
+                }
+                self.tables.insert(
+                    id,
+                    TableManifest {
+                        level,
+                        smallest: smallest.clone(),
+                        biggest: biggest.clone(),
+                    },
+                );
+                self.creations += 1;
+            }
+            ManifestChange::DeleteTable { level, id } => {
+                match self.tables.get(&id) {
+                    Some(t) if t.level == level => {}
+                    _ => return Err(ManifestError::MissingTable { id, level }),
+                }
+                self.tables.remove(&id);
+                self.deletions += 1;
+            }
+            ManifestChange::SetHead(head) => self.head = head,
+            ManifestChange::SetNextFileId(id) => self.next_file_id = id,
+            ManifestChange::SetMaxVersion(version) => {
+                self.max_version = self.max_version.max(version)
+            }
+        }
+        Ok(())
+    }
+
+    // Changes to build the state from scratch.
+    fn snapshot(&self) -> Vec<ManifestChange> {
+        let mut ids: Vec<u64> = self.tables.keys().cloned().collect();
+        ids.sort();
+        let mut changes: Vec<ManifestChange> = ids.into_iter()
+            .map(|id| {
+                let t = &self.tables[&id];
+                ManifestChange::AddTable {
+                    level: t.level,
+                    id,
+                    smallest: t.smallest.clone(),
+                    biggest: t.biggest.clone(),
+                }
+            })
+            .collect();
+        changes.push(ManifestChange::SetHead(self.head));
+        changes.push(ManifestChange::SetNextFileId(self.next_file_id));
+        changes.push(ManifestChange::SetMaxVersion(self.max_version));
+        changes
+    }
+}
+
+/// The append-only log of `ManifestChange`s in file `MANIFEST`:
+///
+/// ```text
+/// | magic (u32) | version (u32) | record | record | ...
+/// ```
+///
+/// where each record is `| len (u32) | crc32 (u32) | change count (u32) | change | ... |`,
+/// the crc32 covers the bytes after it.
+/// The log is rewritten with the current state once it has too many deletions.
+pub struct ManifestFile {
+    dir: PathBuf,
+    file: File,
+    manifest: Manifest,
+    rewrite_threshold: usize,
+    // whether a torn record is truncated on open.
+    truncated: bool,
+}
+
+impl ManifestFile {
+    /// Open the manifest in `dir` and replay it, or create an empty one.
+    /// A torn record at the end is truncated, while a corrupted record is an error.
+    pub fn open(dir: &Path) -> Result<ManifestFile, Error> {
+        let path = dir.join(MANIFEST_FILE);
+        let mut file = match OpenOptions::new().read(true).append(true).open(&path) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                let manifest = Manifest::default();
+                return Ok(ManifestFile {
+                    dir: dir.to_path_buf(),
+                    file: Self::create(dir, &manifest)?,
+                    manifest,
+                    rewrite_threshold: REWRITE_THRESHOLD,
+                    truncated: false,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut buf = vec![];
+        file.read_to_end(&mut buf)?;
+        let (manifest, end) = Self::replay(&buf)?;
+        let truncated = end < buf.len();
+        if truncated {
+            file.set_len(end as u64)?;
+            file.sync_all()?;
+        }
+        Ok(ManifestFile {
+            dir: dir.to_path_buf(),
+            file,
+            manifest,
+            rewrite_threshold: REWRITE_THRESHOLD,
+            truncated,
+        })
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Whether a torn record at the end is truncated when the manifest is opened.
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Apply `changes` as a whole, they are persisted once it returns.
+    pub fn add_changes(&mut self, changes: &[ManifestChange]) -> Result<(), Error> {
+        let mut manifest = self.manifest.clone();
+        for change in changes {
+            manifest.apply(change)?;
+        }
+        self.file.write_all(&Self::encode_record(changes))?;
+        self.file.sync_all()?;
+        self.manifest = manifest;
+
+        let m = &self.manifest;
+        if m.deletions > self.rewrite_threshold
+            && m.deletions > REWRITE_RATIO * (m.creations - m.deletions)
+        {
+            self.rewrite()?;
+        }
+        Ok(())
+    }
+
+    // Replace the log with a record of the current state.
+    fn rewrite(&mut self) -> Result<(), Error> {
+        self.file = Self::create(&self.dir, &self.manifest)?;
+        self.manifest.creations = self.manifest.tables.len();
+        self.manifest.deletions = 0;
+        Ok(())
+    }
+
+    // Write a log with a record of `manifest` by renaming a synced temp file,
+    // and open it for appending.
+    fn create(dir: &Path, manifest: &Manifest) -> io::Result<File> {
+        let path = dir.join(MANIFEST_FILE);
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_u32::<BigEndian>(MAGIC)?;
+            tmp.write_u32::<BigEndian>(VERSION)?;
+            tmp.write_all(&Self::encode_record(&manifest.snapshot()))?;
+            tmp.sync_all()?;
+        }
+        rename(&tmp_path, &path)?;
+        File::open(dir)?.sync_all()?;
+        OpenOptions::new().append(true).open(&path)
+    }
+
+    fn encode_record(changes: &[ManifestChange]) -> Vec<u8> {
+        let mut payload = vec![];
+        // writing into a vec never fails.
+        payload.write_u32::<BigEndian>(changes.len() as u32).unwrap();
+        for change in changes {
+            change.encode(&mut payload).unwrap();
+        }
+        let mut buf = Vec::with_capacity(payload.len() + 8);
+        buf.write_u32::<BigEndian>(payload.len() as u32).unwrap();
+        buf.write_u32::<BigEndian>(crc32::checksum_castagnoli(&payload))
+            .unwrap();
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    // Replay records in `buf`, return the state and the end offset of the last whole record.
+    fn replay(buf: &[u8]) -> Result<(Manifest, usize), Error> {
+        let mut reader = buf;
+        let magic = reader.read_u32::<BigEndian>()?;
+        let version = reader.read_u32::<BigEndian>()?;
+        if magic != MAGIC || version != VERSION {
+            Err(ManifestError::BadMagic { magic, version })?
+        }
+        let mut manifest = Manifest::default();
+        let mut offset = 8;
+        while reader.len() >= 8 {
+            let len = (&reader[..4]).read_u32::<BigEndian>()? as usize;
+            let crc = (&reader[4..8]).read_u32::<BigEndian>()?;
+            if reader.len() < 8 + len {
+                // only the last record can be torn, so it can't be longer than the file
+                // and no whole record can follow it.
+                if len > buf.len() || Self::has_record(&reader[8..]) {
+                    Err(ManifestError::CorruptedLength {
+                        offset: offset as u64,
+                    })?
+                }
+                break;
+            }
+            let mut payload = &reader[8..8 + len];
+            if crc32::checksum_castagnoli(payload) != crc {
+                Err(ManifestError::ChecksumMismatch {
+                    offset: offset as u64,
+                })?
+            }
+            let count = payload.read_u32::<BigEndian>()?;
+            for _ in 0..count {
+                manifest.apply(&ManifestChange::decode(&mut payload)?)?;
+            }
+            reader = &reader[8 + len..];
+            offset += 8 + len;
+        }
+        Ok((manifest, offset))
+    }
+
+    // Whether a whole record with a matching checksum starts anywhere in `buf`.
+    fn has_record(buf: &[u8]) -> bool {
+        (0..buf.len()).any(|start| {
+            let rest = &buf[start..];
+            if rest.len() < 12 {
+                return false;
+            }
+            let len = BigEndian::read_u32(&rest[..4]) as usize;
+            let crc = BigEndian::read_u32(&rest[4..8]);
+            // a payload has at least the change count.
+            len >= 4 && rest.len() >= 8 + len
+                && crc32::checksum_castagnoli(&rest[8..8 + len]) == crc
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn add(level: u32, id: u64) -> ManifestChange {
+        ManifestChange::AddTable {
+            level,
+            id,
+            smallest: format!("a{}", id).into_bytes(),
+            biggest: format!("z{}", id).into_bytes(),
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let tmp_dir = ::tempdir::TempDir::new("manifest").unwrap();
+        {
+            let mut mf = ManifestFile::open(tmp_dir.path()).unwrap();
+            assert!(mf.manifest().tables.is_empty());
+            mf.add_changes(&[add(0, 1), ManifestChange::SetNextFileId(2)])
+                .unwrap();
+            mf.add_changes(&[
+                add(0, 2),
+                ManifestChange::SetHead(ValuePointer::new(1, 2, 3)),
+                ManifestChange::SetNextFileId(3),
+                ManifestChange::SetMaxVersion(10),
+            ]).unwrap();
+            mf.add_changes(&[ManifestChange::SetMaxVersion(8)]).unwrap();
+            mf.add_changes(&[ManifestChange::DeleteTable { level: 0, id: 1 }, add(1, 3)])
+                .unwrap();
+            // an invalid edit is not applied.
+            assert!(mf.add_changes(&[add(2, 4), add(2, 2)]).is_err());
+            assert!(mf.add_changes(&[ManifestChange::DeleteTable { level: 1, id: 2 }])
+                .is_err());
+        }
+        let mf = ManifestFile::open(tmp_dir.path()).unwrap();
+        let m = mf.manifest();
+        assert_eq!(2, m.tables.len());
+        assert_eq!(0, m.tables[&2].level);
+        assert_eq!(b"a2".to_vec(), m.tables[&2].smallest);
+        assert_eq!(b"z2".to_vec(), m.tables[&2].biggest);
+        assert_eq!(1, m.tables[&3].level);
+        assert_eq!(ValuePointer::new(1, 2, 3), m.head);
+        assert_eq!(3, m.next_file_id);
+        assert_eq!(10, m.max_version);
+    }
+
+    #[test]
+    fn test_torn_and_corrupted_record() {
+        let tmp_dir = ::tempdir::TempDir::new("manifest").unwrap();
+        let path = tmp_dir.path().join(MANIFEST_FILE);
+        {
+            let mut mf = ManifestFile::open(tmp_dir.path()).unwrap();
+            mf.add_changes(&[add(0, 1)]).unwrap();
+            mf.add_changes(&[add(0, 2)]).unwrap();
+        }
+        let size = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 1)
+            .unwrap();
+        {
+            let mut mf = ManifestFile::open(tmp_dir.path()).unwrap();
+            assert_eq!(vec![&1], mf.manifest().tables.keys().collect::<Vec<_>>());
+            mf.add_changes(&[add(0, 3)]).unwrap();
+        }
+        assert_eq!(2, ManifestFile::open(tmp_dir.path()).unwrap().manifest().tables.len());
+
+        let mut buf = fs::read(&path).unwrap();
+        let last = buf.len() - 1;
+        buf[last] ^= 0xff;
+        fs::write(&path, &buf).unwrap();
+        match ManifestFile::open(tmp_dir.path()) {
+            Err(e) => match e.downcast::<ManifestError>() {
+                Ok(ManifestError::ChecksumMismatch { .. }) => {}
+                Ok(e) => panic!("{}", e),
+                Err(e) => panic!("{}", e),
+            },
+            Ok(_) => panic!("corrupted manifest is opened"),
+        }
+    }
+
+    #[test]
+    fn test_corrupted_length() {
+        let tmp_dir = ::tempdir::TempDir::new("manifest").unwrap();
+        let path = tmp_dir.path().join(MANIFEST_FILE);
+        {
+            let mut mf = ManifestFile::open(tmp_dir.path()).unwrap();
+            for id in 1..5 {
+                mf.add_changes(&[add(0, id)]).unwrap();
+            }
+        }
+        let buf = fs::read(&path).unwrap();
+        // the length of the first record added, followed by 3 whole records.
+        let snapshot_len = BigEndian::read_u32(&buf[8..12]) as usize;
+        let pos = 8 + 8 + snapshot_len;
+        let size = buf.len() as u32;
+        // longer than the file, or than the rest of it.
+        for &len in &[size + 1, size - pos as u32 - 1] {
+            let mut buf = buf.clone();
+            BigEndian::write_u32(&mut buf[pos..pos + 4], len);
+            fs::write(&path, &buf).unwrap();
+            match ManifestFile::open(tmp_dir.path()) {
+                Err(e) => match e.downcast::<ManifestError>() {
+                    Ok(ManifestError::CorruptedLength { offset }) => {
+                        assert_eq!(pos as u64, offset)
+                    }
+                    Ok(e) => panic!("{}", e),
+                    Err(e) => panic!("{}", e),
+                },
+                Ok(_) => panic!("corrupted manifest is opened"),
+            }
+            // the manifest is not truncated.
+            assert_eq!(buf, fs::read(&path).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let tmp_dir = ::tempdir::TempDir::new("manifest").unwrap();
+        let path = tmp_dir.path().join(MANIFEST_FILE);
+        let mut mf = ManifestFile::open(tmp_dir.path()).unwrap();
+        mf.rewrite_threshold = 10;
+        mf.add_changes(&[add(1, 1)]).unwrap();
+        for id in 2..13 {
+            mf.add_changes(&[add(0, id)]).unwrap();
+            mf.add_changes(&[ManifestChange::DeleteTable { level: 0, id }])
+                .unwrap();
+        }
+        // rewritten on the 11th deletion.
+        assert_eq!(0, mf.manifest().deletions);
+        mf.add_changes(&[ManifestChange::SetNextFileId(13)]).unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+        assert!(size < 200, "{}", size);
+
+        let mf = ManifestFile::open(tmp_dir.path()).unwrap();
+        let m = mf.manifest();
+        assert_eq!(vec![&1], m.tables.keys().collect::<Vec<_>>());
+        assert_eq!(13, m.next_file_id);
+    }
+}
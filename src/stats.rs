@@ -0,0 +1,24 @@
+/// Counters of background work and write stalls since the `DB` is opened, see `DB::stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Bytes of tables written by memtable flushes.
+    pub flushed_bytes: u64,
+    /// Bytes of tables written by compactions, tables moved to another level are not counted.
+    pub compacted_bytes: u64,
+    /// Number of compactions which rewrote tables.
+    pub compactions: u64,
+    /// Number of writes delayed since level 0 has many tables.
+    pub write_slowdowns: u64,
+    /// Number of writes blocked until flushes or compactions catch up.
+    pub write_stops: u64,
+}
+
+impl Stats {
+    /// Bytes of tables written per byte flushed from memtables, 0 if nothing is flushed yet.
+    pub fn write_amplification(&self) -> f64 {
+        if self.flushed_bytes == 0 {
+            return 0.0;
+        }
+        (self.flushed_bytes + self.compacted_bytes) as f64 / self.flushed_bytes as f64
+    }
+}
@@ -0,0 +1,137 @@
+/// Bloom filter over user keys of a table, in the format of LevelDB:
+/// the bit array followed by a byte of the number of probes.
+pub struct Bloom {
+    data: Vec<u8>,
+}
+
+/// Default bits of the filter per key, a false positive rate about 1%.
+pub const DEFAULT_BITS_PER_KEY: usize = 10;
+
+impl Bloom {
+    /// Build a filter from hashes of keys, `bits_per_key` of 0 builds an empty filter.
+    pub fn build(hashes: &[u32], bits_per_key: usize) -> Bloom {
+        if bits_per_key == 0 {
+            return Bloom { data: vec![] };
+        }
+        // 0.69 is approximately ln(2), which minimizes the false positive rate.
+        let k = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        // a small filter has a high false positive rate, so use at least 64 bits.
+        let bits = (hashes.len() * bits_per_key).max(64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let mut data = vec![0u8; bytes + 1];
+        for &h in hashes {
+            let mut h = h;
+            // double hashing, see "Less Hashing, Same Performance".
+            let delta = h.rotate_left(15);
+            for _ in 0..k {
+                let pos = h as usize % bits;
+                data[pos / 8] |= 1 << (pos % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+        data[bytes] = k;
+        Bloom { data }
+    }
+
+    pub fn decode(data: &[u8]) -> Bloom {
+        Bloom {
+            data: data.to_vec(),
+        }
+    }
+
+    pub fn encode(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns false only if the key of `hash` is definitely not in the filter.
+    pub fn may_contain(&self, hash: u32) -> bool {
+        let (k, bits) = match self.data.split_last() {
+            Some((&k, bits)) if !bits.is_empty() => (k, bits),
+            // no filter
+            _ => return true,
+        };
+        // reserved for new encodings.
+        if k > 30 {
+            return true;
+        }
+        let nbits = bits.len() * 8;
+        let mut h = hash;
+        let delta = h.rotate_left(15);
+        for _ in 0..k {
+            let pos = h as usize % nbits;
+            if bits[pos / 8] & (1 << (pos % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+/// Hash of a key used by the filter, the murmur-like hash of LevelDB.
+pub fn hash(key: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f_1d34;
+    const M: u32 = 0xc6a4_a793;
+    let mut h = SEED ^ (key.len() as u32).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(4);
+    for c in &mut chunks {
+        let w = u32::from(c[0])
+            | u32::from(c[1]) << 8
+            | u32::from(c[2]) << 16
+            | u32::from(c[3]) << 24;
+        h = h.wrapping_add(w).wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate().rev() {
+            h = h.wrapping_add(u32::from(b) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(n: usize, tag: &str) -> Vec<Vec<u8>> {
+        (0..n).map(|i| format!("{}{}", tag, i).into_bytes()).collect()
+    }
+
+    #[test]
+    fn test_small_bloom() {
+        let hashes: Vec<u32> = [b"hello", b"world"].iter().map(|k| hash(*k)).collect();
+        let bloom = Bloom::build(&hashes, 10);
+        assert!(bloom.may_contain(hash(b"hello")));
+        assert!(bloom.may_contain(hash(b"world")));
+        assert!(!bloom.may_contain(hash(b"x")));
+        assert!(!bloom.may_contain(hash(b"foo")));
+    }
+
+    #[test]
+    fn test_empty_bloom() {
+        let bloom = Bloom::build(&[hash(b"hello")], 0);
+        assert!(bloom.encode().is_empty());
+        assert!(bloom.may_contain(hash(b"x")));
+        assert!(Bloom::decode(&[]).may_contain(hash(b"x")));
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        let hashes: Vec<u32> = keys(10000, "key").iter().map(|k| hash(k)).collect();
+        let bloom = Bloom::decode(Bloom::build(&hashes, 10).encode());
+        for &h in hashes.iter() {
+            assert!(bloom.may_contain(h));
+        }
+        let false_positives = keys(10000, "other")
+            .iter()
+            .filter(|k| bloom.may_contain(hash(k)))
+            .count();
+        assert!(false_positives < 200, "{}", false_positives);
+    }
+}
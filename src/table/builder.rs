@@ -1,3 +1,267 @@
-use table::Table;
+use super::bloom::{self, Bloom, DEFAULT_BITS_PER_KEY};
+use super::Header;
+use byteorder::{BigEndian, WriteBytesExt};
+use keys::{compare_keys, parse_key, same_key};
+use lsm::lcp;
+use std::cmp::Ordering;
 
-struct TableBuilder {}
+/// Default size of blocks in a table.
+pub const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+/// Number of entries between restart points in a block.
+pub const RESTART_INTERVAL: usize = 16;
+
+/// `TableBuilder` encodes sorted key-value pairs into the layout `Table::open` reads:
+///
+/// ```text
+/// | block | ... | block end offsets (u32 each) | block count (u32) | bloom | bloom len (u32) |
+/// ```
+///
+/// Each block is a list of `header | key diff | value` followed by restart points,
+/// where keys are prefix-compressed against the key at the last restart point,
+/// see `Block` for details.
+pub struct TableBuilder {
+    buf: Vec<u8>,
+    block_size: usize,
+    // the key at the last restart point.
+    base_key: Vec<u8>,
+    base_offset: u32,
+    // restart points of the current block.
+    restarts: Vec<u32>,
+    // number of entries since the last restart point.
+    counter: usize,
+    // offset of the previous entry, relative to the current block.
+    prev_offset: u32,
+    // end offsets of finished blocks.
+    block_ends: Vec<u32>,
+    last_key: Vec<u8>,
+    // hashes of user keys for the bloom filter.
+    key_hashes: Vec<u32>,
+    bloom_bits_per_key: usize,
+}
+
+impl Default for TableBuilder {
+    fn default() -> Self {
+        TableBuilder::new(DEFAULT_BLOCK_SIZE, DEFAULT_BITS_PER_KEY)
+    }
+}
+
+impl TableBuilder {
+    /// Create a builder which cuts blocks at about `block_size` bytes,
+    /// and builds a bloom filter of `bloom_bits_per_key`, 0 means no filter.
+    pub fn new(block_size: usize, bloom_bits_per_key: usize) -> TableBuilder {
+        TableBuilder {
+            buf: Vec::with_capacity(block_size * 16),
+            block_size,
+            base_key: vec![],
+            base_offset: 0,
+            restarts: vec![],
+            counter: 0,
+            prev_offset: u32::MAX,
+            block_ends: vec![],
+            last_key: vec![],
+            key_hashes: vec![],
+            bloom_bits_per_key,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Size of the table if it's finished now.
+    pub fn estimated_size(&self) -> usize {
+        let bloom_size = self.key_hashes.len() * self.bloom_bits_per_key / 8 + 1;
+        let restarts_size = 4 * (self.restarts.len() + 1);
+        self.buf.len() + restarts_size + 4 * (self.block_ends.len() + 1) + 4 + bloom_size + 4
+    }
+
+    /// Add an entry to the table.
+    /// Keys must be added in ascending order of `keys::compare_keys`.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        assert!(!key.is_empty() && key.len() <= u16::MAX as usize);
+        assert!(value.len() <= u16::MAX as usize);
+        assert!(
+            self.is_empty() || compare_keys(&self.last_key, key) == Ordering::Less,
+            "keys should be added in ascending order"
+        );
+        // versions of a key are added together.
+        if self.is_empty() || !same_key(&self.last_key, key) {
+            self.key_hashes.push(bloom::hash(parse_key(key)));
+        }
+
+        if self.restarts.is_empty() || self.block_len() >= self.block_size {
+            self.finish_block();
+            self.base_offset = self.buf.len() as u32;
+        }
+        // the key at a restart point is stored in full.
+        let plen = if self.restarts.is_empty() || self.counter >= RESTART_INTERVAL {
+            self.restarts.push(self.block_len() as u32);
+            self.base_key.clear();
+            self.base_key.extend_from_slice(key);
+            self.counter = 0;
+            0
+        } else {
+            lcp(key, &self.base_key).len()
+        };
+        self.counter += 1;
+        let header = Header {
+            plen: plen as u16,
+            klen: (key.len() - plen) as u16,
+            vlen: value.len() as u16,
+            prev: self.prev_offset,
+        };
+        self.prev_offset = self.block_len() as u32;
+        // writing into a vec never fails.
+        header.encode(&mut self.buf).unwrap();
+        self.buf.extend_from_slice(&key[plen..]);
+        self.buf.extend_from_slice(value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+    }
+
+    /// Finish the table and return its content.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.finish_block();
+        for &end in self.block_ends.iter() {
+            self.buf.write_u32::<BigEndian>(end).unwrap();
+        }
+        self.buf
+            .write_u32::<BigEndian>(self.block_ends.len() as u32)
+            .unwrap();
+        let bloom = Bloom::build(&self.key_hashes, self.bloom_bits_per_key);
+        self.buf.extend_from_slice(bloom.encode());
+        self.buf
+            .write_u32::<BigEndian>(bloom.encode().len() as u32)
+            .unwrap();
+        self.buf
+    }
+
+    fn block_len(&self) -> usize {
+        self.buf.len() - self.base_offset as usize
+    }
+
+    fn finish_block(&mut self) {
+        if self.restarts.is_empty() {
+            return;
+        }
+        for &restart in self.restarts.iter() {
+            self.buf.write_u32::<BigEndian>(restart).unwrap();
+        }
+        self.buf
+            .write_u32::<BigEndian>(self.restarts.len() as u32)
+            .unwrap();
+        self.restarts.clear();
+        self.block_ends.push(self.buf.len() as u32);
+        self.prev_offset = u32::MAX;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::key_with_ts;
+    use std::fs;
+    use std::io::Write;
+    use std::sync::Arc;
+    use table::{Table, TableLoadMode};
+
+    fn open_table(dir: &::std::path::Path, buf: &[u8], mode: TableLoadMode) -> Arc<Table> {
+        let path = dir.join("1.sst");
+        fs::File::create(&path).unwrap().write_all(buf).unwrap();
+        Arc::new(Table::open(1, fs::File::open(&path).unwrap(), mode).unwrap())
+    }
+
+    fn kvs(n: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..n)
+            .map(|i| {
+                let key = key_with_ts(format!("key{:05}", i).as_bytes(), 1);
+                (key, format!("value{}", i).into_bytes())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_build_and_iterate() {
+        let tmp_dir = ::tempdir::TempDir::new("table").unwrap();
+        let kvs = kvs(1000);
+        let mut builder = TableBuilder::new(256, DEFAULT_BITS_PER_KEY);
+        for (k, v) in kvs.iter() {
+            builder.add(k, v);
+        }
+        let buf = builder.finish();
+
+        let modes = [TableLoadMode::LoadToRAM, TableLoadMode::MemoryMap, TableLoadMode::FileIO];
+        for &mode in modes.iter() {
+            let t = open_table(tmp_dir.path(), &buf, mode);
+            assert!(t.block_index.len() > 1);
+            assert_eq!(buf.len() as u64, t.size());
+            assert_eq!(&kvs[0].0[..], t.smallest());
+            assert_eq!(&kvs[999].0[..], t.biggest());
+            assert_eq!(kvs, t.iter().collect::<Vec<_>>());
+
+            let mut it = t.iter();
+            it.seek(&kvs[500].0);
+            assert_eq!(&kvs[500..], &it.collect::<Vec<_>>()[..]);
+        }
+    }
+
+    #[test]
+    fn test_versions_of_key() {
+        let tmp_dir = ::tempdir::TempDir::new("table").unwrap();
+        let kvs = vec![
+            (key_with_ts(b"a", 3), b"3".to_vec()),
+            (key_with_ts(b"a", 1), b"1".to_vec()),
+            (key_with_ts(b"ab", 2), vec![]),
+        ];
+        let mut builder = TableBuilder::default();
+        for (k, v) in kvs.iter() {
+            builder.add(k, v);
+        }
+        let t = open_table(tmp_dir.path(), &builder.finish(), TableLoadMode::LoadToRAM);
+        assert_eq!(kvs, t.iter().collect::<Vec<_>>());
+        assert!(t.may_contain(b"a"));
+        assert!(t.may_contain(b"ab"));
+        assert!(!t.may_contain(b"b"));
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let tmp_dir = ::tempdir::TempDir::new("table").unwrap();
+        let kvs = kvs(1000);
+        for &bits_per_key in [0, DEFAULT_BITS_PER_KEY].iter() {
+            let mut builder = TableBuilder::new(DEFAULT_BLOCK_SIZE, bits_per_key);
+            for (k, v) in kvs.iter() {
+                builder.add(k, v);
+            }
+            let t = open_table(tmp_dir.path(), &builder.finish(), TableLoadMode::LoadToRAM);
+            for (k, _) in kvs.iter() {
+                assert!(t.may_contain(parse_key(k)));
+            }
+            let false_positives = (0..1000)
+                .filter(|i| t.may_contain(format!("other{:05}", i).as_bytes()))
+                .count();
+            if bits_per_key == 0 {
+                assert_eq!(1000, false_positives);
+            } else {
+                assert!(false_positives < 50, "{}", false_positives);
+            }
+        }
+    }
+
+    #[test]
+    fn test_empty_table() {
+        let tmp_dir = ::tempdir::TempDir::new("table").unwrap();
+        let builder = TableBuilder::default();
+        assert!(builder.is_empty());
+        let t = open_table(tmp_dir.path(), &builder.finish(), TableLoadMode::LoadToRAM);
+        assert_eq!(0, t.iter().count());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_add_out_of_order() {
+        let mut builder = TableBuilder::default();
+        builder.add(&key_with_ts(b"b", 1), b"");
+        builder.add(&key_with_ts(b"a", 1), b"");
+    }
+}
@@ -0,0 +1,155 @@
+use super::Block;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// Number of shards of `BlockCache`, each of them has its own lock.
+const SHARDS: usize = 16;
+
+/// Blocks are identified by the table id and the offset of the block in the table.
+pub type BlockKey = (u64, u32);
+
+/// A LRU cache of blocks shared by tables opened with `TableLoadMode::FileIO`.
+/// It's split into shards by keys, and each shard evicts its least recently used blocks
+/// once the blocks in it use more than its part of the capacity.
+pub struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+}
+
+struct Shard {
+    capacity: usize,
+    usage: usize,
+    // blocks with their size and the tick of the last access.
+    blocks: HashMap<BlockKey, (Block, usize, u64)>,
+    // keys ordered by the last access, the least recently used first.
+    lru: BTreeMap<u64, BlockKey>,
+    tick: u64,
+}
+
+impl Shard {
+    fn touch(&mut self, key: BlockKey) -> Option<Block> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.blocks.get_mut(&key)?;
+        self.lru.remove(&entry.2);
+        self.lru.insert(tick, key);
+        entry.2 = tick;
+        Some(entry.0.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, block: Block, charge: usize) {
+        if let Some((_, old_charge, old_tick)) = self.blocks.remove(&key) {
+            self.usage -= old_charge;
+            self.lru.remove(&old_tick);
+        }
+        if charge > self.capacity {
+            return;
+        }
+        while self.usage + charge > self.capacity {
+            let (&tick, &evicted) = self.lru.iter().next().unwrap();
+            self.lru.remove(&tick);
+            let (_, evicted_charge, _) = self.blocks.remove(&evicted).unwrap();
+            self.usage -= evicted_charge;
+        }
+        self.tick += 1;
+        self.usage += charge;
+        self.lru.insert(self.tick, key);
+        self.blocks.insert(key, (block, charge, self.tick));
+    }
+}
+
+impl BlockCache {
+    /// Create a cache holding blocks of about `capacity` bytes in total.
+    pub fn new(capacity: usize) -> BlockCache {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    capacity: capacity / SHARDS,
+                    usage: 0,
+                    blocks: HashMap::new(),
+                    lru: BTreeMap::new(),
+                    tick: 0,
+                })
+            })
+            .collect();
+        BlockCache { shards }
+    }
+
+    pub fn get(&self, key: BlockKey) -> Option<Block> {
+        self.shard(key).lock().unwrap().touch(key)
+    }
+
+    /// Cache `block` which takes `charge` bytes, it's dropped at once if it doesn't fit in a shard.
+    pub fn insert(&self, key: BlockKey, block: Block, charge: usize) {
+        self.shard(key).lock().unwrap().insert(key, block, charge)
+    }
+
+    /// Bytes of cached blocks.
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().usage).sum()
+    }
+
+    fn shard(&self, key: BlockKey) -> &Mutex<Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(len: usize) -> Block {
+        Block {
+            data: vec![0; len].into(),
+            restarts: vec![0].into(),
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = BlockCache::new(SHARDS * 100);
+        // keys in the same shard.
+        let keys: Vec<BlockKey> = (0..1000)
+            .map(|offset| (1, offset))
+            .filter(|&key| ::std::ptr::eq(cache.shard(key), cache.shard((1, 0))))
+            .take(3)
+            .collect();
+        cache.insert(keys[0], block(40), 40);
+        cache.insert(keys[1], block(40), 40);
+        assert_eq!(80, cache.usage());
+        assert_eq!(40, cache.get(keys[0]).unwrap().len());
+
+        // keys[1] is the least recently used.
+        cache.insert(keys[2], block(40), 40);
+        assert_eq!(80, cache.usage());
+        assert!(cache.get(keys[0]).is_some());
+        assert!(cache.get(keys[1]).is_none());
+        assert!(cache.get(keys[2]).is_some());
+
+        // replaced by a bigger block.
+        cache.insert(keys[2], block(60), 60);
+        assert_eq!(100, cache.usage());
+        assert_eq!(60, cache.get(keys[2]).unwrap().len());
+
+        // too big to be cached.
+        cache.insert(keys[1], block(101), 101);
+        assert!(cache.get(keys[1]).is_none());
+        assert_eq!(100, cache.usage());
+    }
+
+    #[test]
+    fn test_shards() {
+        let cache = BlockCache::new(SHARDS * 100);
+        for offset in 0..1000 {
+            cache.insert((2, offset), block(10), 10);
+        }
+        assert!(cache.usage() <= SHARDS * 100);
+        assert!(cache.usage() > SHARDS * 50);
+        // the recent blocks are kept.
+        assert!(cache.get((2, 999)).is_some());
+        assert!(cache.get((2, 0)).is_none());
+    }
+}
@@ -1,45 +1,66 @@
 use super::*;
 use failure::Error;
-use std::convert::From;
+use keys::{compare_keys, KeyRange};
+use std::cmp::Ordering;
+use std::sync::Arc;
 
-impl<'a> IntoIterator for Block<'a> {
+impl IntoIterator for Block {
     type Item = (Vec<u8>, Vec<u8>);
-    type IntoIter = BlockIterator<'a>;
+    type IntoIter = BlockIterator;
 
     fn into_iter(self) -> Self::IntoIter {
+        let end = self.len() as u32;
         BlockIterator {
             block: self,
             pos: 0,
+            end,
+            back_pos: None,
             base_key: vec![],
             last: None,
         }
     }
 }
 
-pub struct BlockIterator<'b> {
-    block: Block<'b>,
+/// A double-ended iterator over entries of a block,
+/// `next` and `next_back` stop once they meet each other.
+pub struct BlockIterator {
+    block: Block,
     pos: u32, // position in block's data
+    // entries from `end` are consumed by `next_back`.
+    end: u32,
+    // position of the entry ending at `end`, found by the last `next_back` or seek.
+    back_pos: Option<u32>,
     base_key: Vec<u8>,
     last: Option<Result<Header, Error>>,
 }
 
-impl<'a> BlockIterator<'a> {
+impl BlockIterator {
     // get err if errored.
     pub fn err(&self) -> Option<&Error> {
         self.last.as_ref().and_then(|l| l.as_ref().err())
     }
+    // take the error out if errored.
+    pub fn take_err(&mut self) -> Option<Error> {
+        match self.last.take() {
+            Some(Err(e)) => Some(e),
+            last => {
+                self.last = last;
+                None
+            }
+        }
+    }
     pub fn reset(&mut self) {
         self.pos = 0;
+        self.end = self.block.len() as u32;
+        self.back_pos = None;
         self.base_key = vec![];
         self.last = None;
     }
 
     // Parse next header-k-v
     fn parse_next(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
-        if (self.pos as usize) == self.block.len() {
+        if self.pos >= self.end {
             return Option::None;
-        } else if self.pos as usize > self.block.len() {
-            panic!("This should not happen")
         }
         let header_res = self.parse_header();
         match header_res {
@@ -49,16 +70,24 @@ impl<'a> BlockIterator<'a> {
             }
             Ok(header) if header.klen == 0 && header.plen == 0 => Option::None,
             Ok(header) => {
-                // Populate baseKey if it isn't set yet. This would only happen for the first Next.
-                if self.base_key.is_empty() {
-                    // This should be the first Next() for this block. Hence, prefix length should be zero.
-                    assert_eq!(header.plen, 0);
-                    let key: &[u8] = &self.block.data
-                        [(self.pos as usize)..(self.pos as usize + header.klen as usize)];
-                    self.base_key = key.to_vec();
-                }
-                match self.parse_kv(&header) {
-                    Ok(kv) => Some(kv),
+                let entry_pos = self.pos - u32::from(Header::SIZE);
+                let is_restart = self.block.restarts.binary_search(&entry_pos).is_ok();
+                let res = if is_restart && header.plen != 0 {
+                    Err(DecodeError::PrefixAtRestart {
+                        pos: entry_pos,
+                        h: header,
+                    }.into())
+                } else {
+                    self.parse_kv(&header)
+                };
+                match res {
+                    Ok(kv) => {
+                        // The key at a restart point is the base key of entries after it.
+                        if is_restart {
+                            self.base_key = kv.0.clone();
+                        }
+                        Some(kv)
+                    }
                     Err(e) => {
                         self.last = Some(Err(e));
                         None
@@ -86,19 +115,16 @@ impl<'a> BlockIterator<'a> {
         // TODO: should we shrunk the capacity to `header.plen + header.ken`
         let mut key: Vec<u8> = vec![];
 
-        // make sure `header.plen` is not greater than len of base_key
-        assert!(header.plen as usize <= self.base_key.len());
-        key.extend_from_slice(&self.base_key[0..header.plen as usize]);
-
-        assert!(self.pos as usize <= self.block.len() - 1);
         let diff_key_range = self.pos as usize..self.pos as usize + header.klen as usize;
-        if diff_key_range.end > self.block.len() {
-            return Err(DecodeError::KeyExceedSizeOfBlock {
+        // `header.plen` can't be greater than len of base_key
+        if header.plen as usize > self.base_key.len() || diff_key_range.end > self.block.len() {
+            Err(DecodeError::KeyExceedSizeOfBlock {
                 pos: self.pos,
                 block_len: self.block.len() as u32,
                 h: *header,
             })?;
         }
+        key.extend_from_slice(&self.base_key[0..header.plen as usize]);
         let diff_key: &[u8] = &self.block.data[diff_key_range];
         self.pos += header.klen as u32;
         key.extend_from_slice(diff_key);
@@ -117,12 +143,13 @@ impl<'a> BlockIterator<'a> {
             })?
         } else {
             let value: &[u8] = &self.block.data[value_range];
+            self.pos += header.vlen as u32;
             Ok(value.to_vec())
         }
     }
 }
 
-impl<'a> Iterator for BlockIterator<'a> {
+impl Iterator for BlockIterator {
     type Item = (Vec<u8>, Vec<u8>);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -133,89 +160,628 @@ impl<'a> Iterator for BlockIterator<'a> {
     }
 }
 
+impl DoubleEndedIterator for BlockIterator {
+    // Entries are walked backward by `Header.prev`.
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if let Some(Err(_)) = self.last {
+            return None;
+        }
+        if self.end <= self.pos {
+            return None;
+        }
+        let res = match self.back_pos {
+            Some(pos) => Ok(pos),
+            None => self.block.last_entry(),
+        }.and_then(|pos| self.block.entry_at(pos).map(|e| (pos, e)));
+        match res {
+            Ok((pos, (header, key, value))) => {
+                self.end = pos;
+                self.back_pos = if header.prev == u32::MAX {
+                    None
+                } else {
+                    Some(header.prev)
+                };
+                Some((key, value))
+            }
+            Err(e) => {
+                self.last = Some(Err(e));
+                None
+            }
+        }
+    }
+}
+
 pub enum SeekFrom {
     Start,
     Current,
 }
 
-impl<'a> BlockIterator<'a> {
-    // seek the first key that >= prefix
-    pub fn seek(&mut self, prefix: &[u8], from: SeekFrom) {
+impl BlockIterator {
+    // Seek to the first key that >= `key` in the order of `keys::compare_keys`,
+    // so that it's returned by the next call of `next`.
+    // Seeking from start binary searches restart points, then scans from the last
+    // restart point whose key < `key`.
+    pub fn seek(&mut self, key: &[u8], from: SeekFrom) {
         self.last = None;
-        match from {
-            SeekFrom::Start => self.reset(),
-            _ => {}
+        if let SeekFrom::Start = from {
+            self.reset();
+            self.pos = self.search_restart(key, Ordering::Less);
+        }
+        loop {
+            let pos = self.pos;
+            match self.next() {
+                Some(kv) => {
+                    if compare_keys(&kv.0, key) != Ordering::Less {
+                        self.pos = pos;
+                        return;
+                    }
+                }
+                None => return,
+            }
+        }
+    }
+
+    // Seek to the last key that <= `key` in the order of `keys::compare_keys`,
+    // so that it's returned by the next call of `next_back`.
+    pub fn seek_for_prev(&mut self, key: &[u8]) {
+        self.reset();
+        self.pos = self.search_restart(key, Ordering::Greater);
+        let mut found = None;
+        loop {
+            let pos = self.pos;
+            match self.next() {
+                Some(kv) => {
+                    if compare_keys(&kv.0, key) == Ordering::Greater {
+                        break;
+                    }
+                    found = Some((pos, self.pos));
+                }
+                None => break,
+            }
         }
-        let found = self.find(|kv| {
-            let key: &[u8] = &kv.0;
-            key >= prefix
+        if self.err().is_some() {
+            return;
+        }
+        self.pos = 0;
+        self.base_key = vec![];
+        match found {
+            Some((pos, end)) => {
+                self.end = end;
+                self.back_pos = Some(pos);
+            }
+            None => self.end = 0,
+        }
+    }
+
+    // Seek to the last key, so that it's returned by the next call of `next_back`.
+    pub fn seek_to_last(&mut self) {
+        self.reset();
+    }
+
+    // Binary search the last restart point whose key compares to `key` as `ord` or equal,
+    // scanning from it finds the entry of `key`.
+    fn search_restart(&self, key: &[u8], ord: Ordering) -> u32 {
+        let block = &self.block;
+        let idx = block.restarts.partition_point(|&r| match block.key_at_restart(r) {
+            Some(k) => {
+                let o = compare_keys(k, key);
+                o == Ordering::Less || (o == Ordering::Equal && ord == Ordering::Greater)
+            }
+            None => false,
         });
+        if idx > 0 {
+            block.restarts[idx - 1]
+        } else {
+            0
+        }
+    }
+}
+
+impl Block {
+    // The full key of the entry at restart point `restart`.
+    fn key_at_restart(&self, restart: u32) -> Option<&[u8]> {
+        let mut buf = self.data.get(restart as usize..)?;
+        let header = Header::decode(&mut buf).ok()?;
+        buf.get(..header.klen as usize)
+    }
+
+    // Position of the last entry, found by scanning from the last restart point.
+    fn last_entry(&self) -> Result<u32, Error> {
+        let mut pos = self.restarts.last().cloned().unwrap_or_default();
+        loop {
+            let mut buf = &self.data[pos as usize..];
+            let header = Header::decode(&mut buf)?;
+            let next = pos + u32::from(Header::SIZE) + u32::from(header.klen)
+                + u32::from(header.vlen);
+            if next as usize >= self.len() {
+                return Ok(pos);
+            }
+            pos = next;
+        }
+    }
+
+    // Decode the entry at `pos`, whose key is prefix-compressed against
+    // the key at the last restart point not after it.
+    fn entry_at(&self, pos: u32) -> Result<(Header, Vec<u8>, Vec<u8>), Error> {
+        let restart_idx = self.restarts.partition_point(|&r| r <= pos);
+        let base_key = match restart_idx.checked_sub(1) {
+            Some(i) => self.key_at_restart(self.restarts[i]).unwrap_or_default(),
+            None => &[],
+        };
+        let mut buf = &self.data[pos as usize..];
+        let header = Header::decode(&mut buf)?;
+        if header.plen != 0 && self.restarts.binary_search(&pos).is_ok() {
+            Err(DecodeError::PrefixAtRestart { pos, h: header })?
+        }
+        let key_end = header.klen as usize;
+        let value_end = key_end + header.vlen as usize;
+        if header.plen as usize > base_key.len() || key_end > buf.len() {
+            Err(DecodeError::KeyExceedSizeOfBlock {
+                pos,
+                block_len: self.len() as u32,
+                h: header,
+            })?
+        }
+        if value_end > buf.len() {
+            Err(DecodeError::ValueExceedSizeOfBlock {
+                pos,
+                block_len: self.len() as u32,
+                h: header,
+            })?
+        }
+        let mut key = Vec::with_capacity(header.plen as usize + key_end);
+        key.extend_from_slice(&base_key[..header.plen as usize]);
+        key.extend_from_slice(&buf[..key_end]);
+        Ok((header, key, buf[key_end..value_end].to_vec()))
     }
 }
 
 impl Table {
-    pub fn iter(&self) -> TableIterator {
-        TableIterator::new(self)
+    pub fn iter(self: &Arc<Self>) -> TableIterator {
+        TableIterator::new(Arc::clone(self))
     }
 }
 
-pub struct TableIterator<'a> {
-    t: &'a Table,
+/// A double-ended iterator over entries of a table.
+/// When both ends are in the same block, they share one block iterator.
+pub struct TableIterator {
+    t: Arc<Table>,
     block_pos: u32,
-    block_iter: Option<BlockIterator<'a>>,
+    block_iter: Option<BlockIterator>,
+    // blocks from `back_block_pos` are consumed by `next_back`.
+    back_block_pos: u32,
+    back_block_iter: Option<BlockIterator>,
+    // blocks out of the range are not loaded.
+    range: KeyRange,
     err: Option<Error>,
 }
 
-impl<'a> TableIterator<'a> {
-    pub fn new(t: &'a Table) -> TableIterator<'a> {
+impl TableIterator {
+    pub fn new(t: Arc<Table>) -> TableIterator {
         TableIterator {
-            t,
             block_pos: 0,
             block_iter: None,
+            back_block_pos: t.block_index.len() as u32,
+            back_block_iter: None,
+            range: KeyRange::full(),
             err: None,
+            t,
         }
     }
 
+    /// Limit the user keys to iterate, blocks out of `range` are skipped.
+    /// Entries in the first and the last block may still be out of the range,
+    /// it's up to the caller to check them.
+    pub fn set_range(&mut self, range: KeyRange) {
+        self.range = range;
+    }
+
+    // Check if all keys of the block at `pos` are out of the range.
+    fn skip_block(&self, pos: u32) -> bool {
+        let index = &self.t.block_index;
+        // keys in the block are >= its first key, and <= the first key of the next block.
+        self.range.is_after(parse_key(&index[pos as usize].prefix))
+            || index
+                .get(pos as usize + 1)
+                .filter(|next| self.range.is_before(parse_key(&next.prefix)))
+                .is_some()
+    }
+
     // Reset iterator to the beginning.
     pub fn reset(&mut self) {
         self.block_pos = 0;
         self.block_iter = None;
+        self.back_block_pos = self.t.block_index.len() as u32;
+        self.back_block_iter = None;
         self.err = None;
     }
 
+    // Seek to the first key that >= `key` in the order of `keys::compare_keys`.
+    // For an internal key with timestamp `ts`, it's the newest version not newer than `ts`.
+    pub fn seek(&mut self, key: &[u8]) {
+        self.reset();
+        let block_pos = match self.t.search_block(key) {
+            Some(pos) => pos,
+            None => return,
+        };
+        match self.t.block(block_pos) {
+            Ok(block) => {
+                let mut block_iter = block.into_iter();
+                block_iter.seek(key, SeekFrom::Start);
+                self.block_pos = block_pos as u32;
+                self.block_iter = Some(block_iter);
+            }
+            Err(e) => self.err = Some(e.into()),
+        }
+    }
+
+    // Seek to the last key that <= `key` in the order of `keys::compare_keys`,
+    // so that it's returned by the next call of `next_back`.
+    // For an internal key with timestamp `ts`, it's the oldest version not older than `ts`,
+    // or the oldest version of the previous key.
+    pub fn seek_for_prev(&mut self, key: &[u8]) {
+        self.reset();
+        let block_pos = match self.t.search_block(key) {
+            Some(pos) => pos,
+            None => return,
+        };
+        if compare_keys(&self.t.block_index[block_pos].prefix, key) == Ordering::Greater {
+            // all keys are greater than `key`.
+            self.back_block_pos = 0;
+            return;
+        }
+        match self.t.block(block_pos) {
+            Ok(block) => {
+                let mut block_iter = block.into_iter();
+                block_iter.seek_for_prev(key);
+                self.back_block_pos = block_pos as u32 + 1;
+                self.back_block_iter = Some(block_iter);
+            }
+            Err(e) => self.err = Some(e.into()),
+        }
+    }
+
+    // Seek to the last key, so that it's returned by the next call of `next_back`.
+    pub fn seek_to_last(&mut self) {
+        self.reset();
+    }
+
     // Get err if any error occurred
     pub fn err(&self) -> Option<&Error> {
         self.err
             .as_ref()
             .or_else(|| self.block_iter.as_ref().and_then(|bi| bi.err()))
+            .or_else(|| self.back_block_iter.as_ref().and_then(|bi| bi.err()))
+    }
+
+    // The iterator of block at `pos`, taken from the other end if they are in the same block.
+    fn load_block(
+        &mut self,
+        pos: u32,
+        other: Option<BlockIterator>,
+    ) -> Option<BlockIterator> {
+        if let Some(it) = other {
+            return Some(it);
+        }
+        match self.t.block(pos as usize) {
+            Ok(block) => Some(block.into_iter()),
+            Err(e) => {
+                self.err = Some(e.into());
+                None
+            }
+        }
     }
 }
 
-impl<'a> Iterator for TableIterator<'a> {
+impl Iterator for TableIterator {
     type Item = (Vec<u8>, Vec<u8>);
 
     fn next(&mut self) -> Option<Self::Item> {
-        // Check if error occurred.
-        if self.err().is_some() {
-            return None;
+        loop {
+            // Check if error occurred or no block left.
+            if self.err().is_some() || self.block_pos >= self.back_block_pos {
+                return None;
+            }
+            if self.block_iter.is_none() {
+                if self.skip_block(self.block_pos) {
+                    self.block_pos += 1;
+                    continue;
+                }
+                let other = if self.block_pos + 1 == self.back_block_pos {
+                    self.back_block_iter.take()
+                } else {
+                    None
+                };
+                self.block_iter = Some(self.load_block(self.block_pos, other)?);
+            }
+            let item = self.block_iter.as_mut().unwrap().next();
+            if item.is_some() || self.err().is_some() {
+                return item;
+            }
+            self.block_pos += 1;
+            self.block_iter = None;
         }
+    }
+}
 
-        // Check if no block left.
-        if self.block_pos as usize >= self.t.block_index.len() {
-            return None;
+impl DoubleEndedIterator for TableIterator {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.err().is_some() || self.back_block_pos <= self.block_pos {
+                return None;
+            }
+            let pos = self.back_block_pos - 1;
+            if self.back_block_iter.is_none() {
+                if self.skip_block(pos) {
+                    self.back_block_pos = pos;
+                    continue;
+                }
+                let other = if pos == self.block_pos {
+                    self.block_iter.take()
+                } else {
+                    None
+                };
+                self.back_block_iter = Some(self.load_block(pos, other)?);
+            }
+            let item = self.back_block_iter.as_mut().unwrap().next_back();
+            if item.is_some() || self.err().is_some() {
+                return item;
+            }
+            self.back_block_pos = pos;
+            self.back_block_iter = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use keys::{key_with_ts, parse_key, KeyRange};
+    use std::fs;
+    use std::io::Write;
+    use std::sync::Arc;
+    use table::builder::{TableBuilder, RESTART_INTERVAL};
+    use table::tests::build_table;
+    use table::{Block, DecodeError, Entry, Header, Table, TableLoadMode};
+
+    fn kv(key: &[u8], ts: u64) -> (Vec<u8>, Vec<u8>) {
+        (key_with_ts(key, ts), ts.to_string().into_bytes())
+    }
+
+    #[test]
+    fn test_iterate() {
+        let blocks = vec![
+            vec![kv(b"a", 3), kv(b"a", 1), kv(b"ab", 2)],
+            vec![kv(b"b", 5), kv(b"c", 1)],
+        ];
+        let t = build_table(&blocks);
+        let all: Vec<_> = blocks.iter().flat_map(|b| b.iter().cloned()).collect();
+        assert_eq!(all, t.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_seek() {
+        let t = build_table(&[
+            vec![kv(b"a", 3), kv(b"a", 1), kv(b"ab", 2)],
+            vec![kv(b"b", 5), kv(b"c", 1)],
+        ]);
+        let mut it = t.iter();
+        let cases = vec![
+            (key_with_ts(b"", u64::MAX), Some(kv(b"a", 3))),
+            (key_with_ts(b"a", u64::MAX), Some(kv(b"a", 3))),
+            (key_with_ts(b"a", 3), Some(kv(b"a", 3))),
+            (key_with_ts(b"a", 2), Some(kv(b"a", 1))),
+            (key_with_ts(b"a", 0), Some(kv(b"ab", 2))),
+            (key_with_ts(b"ab", 1), Some(kv(b"b", 5))),
+            (key_with_ts(b"b", 6), Some(kv(b"b", 5))),
+            (key_with_ts(b"bb", 6), Some(kv(b"c", 1))),
+            (key_with_ts(b"c", 0), None),
+        ];
+        for (key, expected) in cases {
+            it.seek(&key);
+            assert_eq!(expected, it.next());
         }
-        if self.block_iter.is_none() {
-            let block_res = self.t.block(self.block_pos as usize);
-            let block_iter = block_res.unwrap().into_iter();
-            self.block_iter = Some(block_iter);
+
+        // continue iterating after seek
+        it.seek(&key_with_ts(b"ab", 2));
+        assert_eq!(
+            vec![kv(b"ab", 2), kv(b"b", 5), kv(b"c", 1)],
+            it.collect::<Vec<_>>()
+        );
+    }
+
+    // A table of a single block with many restart points,
+    // every key has versions 4 and 2.
+    fn restart_table(dir: &::std::path::Path) -> (Arc<Table>, Vec<Entry>) {
+        let mut builder = TableBuilder::new(1 << 20, 10);
+        let kvs: Vec<_> = (0..500)
+            .flat_map(|i| vec![(i, 4), (i, 2)])
+            .map(|(i, ts)| kv(format!("key{:04}", i * 2).as_bytes(), ts))
+            .collect();
+        for (k, v) in kvs.iter() {
+            builder.add(k, v);
         }
-        let item = self.block_iter.as_mut().unwrap().next();
-        if item.is_some() {
-            item
-        } else {
-            self.block_pos += 1;
-            self.block_iter = None;
-            self.next()
+        let path = dir.join("1.sst");
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(&builder.finish())
+            .unwrap();
+        let t = Table::open(1, fs::File::open(&path).unwrap(), TableLoadMode::LoadToRAM).unwrap();
+        let t = Arc::new(t);
+        (t, kvs)
+    }
+
+    #[test]
+    fn test_seek_with_restarts() {
+        let tmp_dir = ::tempdir::TempDir::new("table").unwrap();
+        let (t, kvs) = restart_table(tmp_dir.path());
+        assert_eq!(1000 / RESTART_INTERVAL + 1, t.block(0).unwrap().restarts.len());
+        assert_eq!(kvs, t.iter().collect::<Vec<_>>());
+
+        let mut it = t.iter();
+        let name = |i: usize| format!("key{:04}", i).into_bytes();
+        for i in 0..1000 {
+            // keys of odd numbers don't exist, the next key is found.
+            let expected = if i % 2 == 0 {
+                vec![Some(kv(&name(i), 2)), Some(kv(&name(i + 2), 4))]
+            } else {
+                vec![Some(kv(&name(i + 1), 4)), Some(kv(&name(i + 1), 2))]
+            };
+            it.seek(&key_with_ts(&name(i), 3));
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|e| e.filter(|kv| parse_key(&kv.0) < &b"key1000"[..]))
+                .collect();
+            assert_eq!(expected, vec![it.next(), it.next()]);
+        }
+    }
+
+    #[test]
+    fn test_iterate_back() {
+        let blocks = vec![
+            vec![kv(b"a", 3), kv(b"a", 1), kv(b"ab", 2)],
+            vec![kv(b"b", 5)],
+            vec![kv(b"c", 1), kv(b"d", 1)],
+        ];
+        let t = build_table(&blocks);
+        let mut all: Vec<_> = blocks.iter().flat_map(|b| b.iter().cloned()).collect();
+        all.reverse();
+        assert_eq!(all, t.iter().rev().collect::<Vec<_>>());
+
+        let mut it = t.iter();
+        it.seek_to_last();
+        assert_eq!(Some(kv(b"d", 1)), it.next_back());
+
+        let tmp_dir = ::tempdir::TempDir::new("table").unwrap();
+        let (t, mut kvs) = restart_table(tmp_dir.path());
+        kvs.reverse();
+        assert_eq!(kvs, t.iter().rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_double_ended() {
+        let blocks = vec![
+            vec![kv(b"a", 3), kv(b"a", 1), kv(b"ab", 2)],
+            vec![kv(b"b", 5)],
+            vec![kv(b"c", 1), kv(b"d", 1)],
+        ];
+        let t = build_table(&blocks);
+        let all: Vec<_> = blocks.iter().flat_map(|b| b.iter().cloned()).collect();
+        // both ends meet in each position.
+        for front in 0..=all.len() {
+            let mut it = t.iter();
+            let mut got: Vec<_> = (0..front).filter_map(|_| it.next()).collect();
+            let mut back: Vec<_> = it.by_ref().rev().collect();
+            assert_eq!(None, it.next());
+            back.reverse();
+            got.extend(back);
+            assert_eq!(all, got);
         }
+        let mut it = t.iter();
+        let mut got = vec![];
+        while let Some(kv) = it.next() {
+            got.push(kv);
+            got.extend(it.next_back());
+        }
+        assert_eq!(all.len(), got.len());
+    }
+
+    #[test]
+    fn test_seek_for_prev() {
+        let t = build_table(&[
+            vec![kv(b"a", 3), kv(b"a", 1), kv(b"ab", 2)],
+            vec![kv(b"b", 5), kv(b"c", 1)],
+        ]);
+        let mut it = t.iter();
+        let cases = vec![
+            (key_with_ts(b"", u64::MAX), None),
+            (key_with_ts(b"a", u64::MAX), None),
+            (key_with_ts(b"a", 3), Some(kv(b"a", 3))),
+            (key_with_ts(b"a", 2), Some(kv(b"a", 3))),
+            (key_with_ts(b"a", 0), Some(kv(b"a", 1))),
+            (key_with_ts(b"ab", 9), Some(kv(b"a", 1))),
+            (key_with_ts(b"b", 6), Some(kv(b"ab", 2))),
+            (key_with_ts(b"b", 5), Some(kv(b"b", 5))),
+            (key_with_ts(b"bb", 6), Some(kv(b"b", 5))),
+            (key_with_ts(b"d", 0), Some(kv(b"c", 1))),
+        ];
+        for (key, expected) in cases {
+            it.seek_for_prev(&key);
+            assert_eq!(expected, it.next_back());
+        }
+
+        // continue iterating backward after seek
+        it.seek_for_prev(&key_with_ts(b"b", 5));
+        assert_eq!(
+            vec![kv(b"b", 5), kv(b"ab", 2), kv(b"a", 1), kv(b"a", 3)],
+            it.rev().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_seek_for_prev_with_restarts() {
+        let tmp_dir = ::tempdir::TempDir::new("table").unwrap();
+        let (t, kvs) = restart_table(tmp_dir.path());
+        let mut it = t.iter();
+        for (i, (k, _)) in kvs.iter().enumerate() {
+            it.seek_for_prev(k);
+            assert_eq!(Some(kvs[i].clone()), it.next_back());
+            assert_eq!(i.checked_sub(1).map(|i| kvs[i].clone()), it.next_back());
+        }
+    }
+
+    #[test]
+    fn test_skip_blocks_out_of_range() {
+        let t = build_table(&[
+            vec![kv(b"a", 1), kv(b"b", 1)],
+            vec![kv(b"c", 1), kv(b"d", 1)],
+            vec![kv(b"e", 1), kv(b"f", 1)],
+        ]);
+        let mut it = t.iter();
+        it.set_range(KeyRange::new(&b"ca"[..]..&b"e"[..]));
+        assert_eq!(vec![kv(b"c", 1), kv(b"d", 1)], it.collect::<Vec<_>>());
+        let mut it = t.iter();
+        it.set_range(KeyRange::new(&b"ca"[..]..&b"e"[..]));
+        assert_eq!(vec![kv(b"d", 1), kv(b"c", 1)], it.rev().collect::<Vec<_>>());
+        // blocks on the bounds are kept.
+        let mut it = t.iter();
+        it.set_range(KeyRange::new(&b"b"[..]..=&b"e"[..]));
+        assert_eq!(6, it.count());
+    }
+
+    // A block of entries encoded from `(plen, key, value)`, with a restart point at 0.
+    fn raw_block(entries: &[(u16, &[u8], &[u8])]) -> Block {
+        let mut data = vec![];
+        for &(plen, key, value) in entries {
+            Header {
+                plen,
+                klen: key.len() as u16,
+                vlen: value.len() as u16,
+                prev: u32::MAX,
+            }.encode(&mut data)
+                .unwrap();
+            data.extend_from_slice(key);
+            data.extend_from_slice(value);
+        }
+        Block {
+            data: Arc::from(data),
+            restarts: Arc::from(vec![0]),
+        }
+    }
+
+    #[test]
+    fn test_corrupted_block() {
+        // a prefix-compressed key at a restart point.
+        let mut it = raw_block(&[(1, b"a", b"v")]).into_iter();
+        assert_eq!(None, it.next());
+        assert!(it.take_err().unwrap().downcast::<DecodeError>().is_ok());
+        let mut it = raw_block(&[(1, b"a", b"v")]).into_iter();
+        assert_eq!(None, it.next_back());
+        assert!(it.take_err().unwrap().downcast::<DecodeError>().is_ok());
+
+        // a prefix longer than the base key.
+        let mut it = raw_block(&[(0, b"a", b"v"), (2, b"b", b"v")]).into_iter();
+        assert_eq!(Some((b"a".to_vec(), b"v".to_vec())), it.next());
+        assert_eq!(None, it.next());
+        assert!(it.take_err().unwrap().downcast::<DecodeError>().is_ok());
     }
 }
@@ -1,7 +1,421 @@
-pub struct Txn {}
+// the impls generated by `derive(Fail)` are non-local.
+#![allow(non_local_definitions)]
 
-impl Txn {
-    pub fn set(key: &[u8], value: &[u8]) {}
+use failure::Error;
+use keys::TS_SIZE;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, MutexGuard};
+use DB;
 
-    fn modify() {}
+#[derive(Debug, Fail)]
+pub enum TxnError {
+    #[fail(display = "Transaction conflicts with a committed one, please retry")]
+    Conflict,
+    #[fail(display = "Write is not allowed in a read-only transaction")]
+    ReadOnly,
+    #[fail(display = "Key of {} bytes is larger than the limit {}", len, max)]
+    KeyTooLarge { len: usize, max: usize },
+}
+
+/// Keys are stored in tables with a timestamp appended, and the length in a u16.
+pub const MAX_KEY_SIZE: usize = u16::MAX as usize - TS_SIZE;
+
+// Write set of a committed transaction, kept until no pending transaction reads before it.
+struct CommittedTxn {
+    ts: u64,
+    conflict_keys: HashSet<u64>,
+}
+
+struct OracleInner {
+    // commit ts of the next committed transaction.
+    next_ts: u64,
+    // commit ts of the last transaction whose writes are applied, new transactions read at it.
+    read_ts: u64,
+    committed_txns: Vec<CommittedTxn>,
+    // read ts -> number of transactions reading at it.
+    pending_reads: BTreeMap<u64, usize>,
+}
+
+/// `Oracle` hands out read and commit timestamps, and detects conflicts between transactions.
+/// Commits are serialized by `write_lock`, which is held while writes are applied,
+/// the oracle lock is only held to check conflicts and hand out timestamps.
+pub(crate) struct Oracle {
+    inner: Mutex<OracleInner>,
+    write_lock: Mutex<()>,
+}
+
+impl Oracle {
+    pub fn new(next_ts: u64) -> Oracle {
+        Oracle {
+            inner: Mutex::new(OracleInner {
+                next_ts,
+                read_ts: next_ts - 1,
+                committed_txns: vec![],
+                pending_reads: BTreeMap::new(),
+            }),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, OracleInner> {
+        self.inner.lock().unwrap()
+    }
+
+    fn write_lock(&self) -> MutexGuard<'_, ()> {
+        self.write_lock.lock().unwrap()
+    }
+
+    fn read_ts(&self) -> u64 {
+        let mut inner = self.lock();
+        let read_ts = inner.read_ts;
+        *inner.pending_reads.entry(read_ts).or_insert(0) += 1;
+        read_ts
+    }
+
+    // Versions overwritten at or before discard ts are invisible to all transactions.
+    pub fn discard_ts(&self) -> u64 {
+        let inner = self.lock();
+        match inner.pending_reads.keys().next() {
+            Some(&ts) => ts,
+            None => inner.read_ts,
+        }
+    }
+
+    fn done_read(&self, read_ts: u64) {
+        let mut inner = self.lock();
+        let remove = match inner.pending_reads.get_mut(&read_ts) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            inner.pending_reads.remove(&read_ts);
+        }
+        inner.cleanup_committed_txns();
+    }
+}
+
+impl OracleInner {
+    // Check if any transaction committed after `txn` started has written a key `txn` read.
+    fn has_conflict(&self, txn: &Txn) -> bool {
+        if txn.reads.is_empty() {
+            return false;
+        }
+        self.committed_txns
+            .iter()
+            .filter(|c| c.ts > txn.read_ts)
+            .any(|c| txn.reads.iter().any(|fp| c.conflict_keys.contains(fp)))
+    }
+
+    fn new_commit_ts(&mut self, txn: &Txn) -> u64 {
+        let ts = self.next_ts;
+        self.next_ts += 1;
+        self.committed_txns.push(CommittedTxn {
+            ts,
+            conflict_keys: txn.writes.keys().map(|k| fingerprint(k)).collect(),
+        });
+        ts
+    }
+
+    // Make writes of the transaction committed at `ts` visible to new transactions.
+    fn done_commit(&mut self, ts: u64) {
+        self.read_ts = ts;
+    }
+
+    // Forget the transaction committed at `ts` whose writes failed,
+    // so that it doesn't conflict with others.
+    fn abort_commit(&mut self, ts: u64) {
+        self.committed_txns.retain(|c| c.ts != ts);
+    }
+
+    // Committed transactions are useless once every pending transaction reads after them.
+    fn cleanup_committed_txns(&mut self) {
+        let max_read_ts = match self.pending_reads.keys().next() {
+            Some(&ts) => ts,
+            None => self.read_ts,
+        };
+        self.committed_txns.retain(|c| c.ts > max_read_ts);
+    }
+}
+
+fn fingerprint(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// An optimistic transaction, writes are buffered locally until `commit`.
+/// A `None` value in `writes` means the key is deleted.
+pub struct Txn<'a> {
+    db: &'a DB,
+    read_ts: u64,
+    read_only: bool,
+    reads: Vec<u64>,
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    done: bool,
+}
+
+impl<'a> Txn<'a> {
+    pub(crate) fn new(db: &'a DB, read_only: bool) -> Txn<'a> {
+        Txn {
+            db,
+            read_ts: db.core.orc.read_ts(),
+            read_only,
+            reads: vec![],
+            writes: BTreeMap::new(),
+            done: false,
+        }
+    }
+
+    #[inline]
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(v) = self.writes.get(key) {
+            return Ok(v.clone());
+        }
+        if !self.read_only {
+            self.reads.push(fingerprint(key));
+        }
+        self.db.get_with_ts(key, self.read_ts)
+    }
+
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.modify(key, Some(value.to_vec()))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), Error> {
+        self.modify(key, None)
+    }
+
+    fn modify(&mut self, key: &[u8], value: Option<Vec<u8>>) -> Result<(), Error> {
+        if self.read_only {
+            Err(TxnError::ReadOnly)?
+        }
+        if key.len() > MAX_KEY_SIZE {
+            Err(TxnError::KeyTooLarge {
+                len: key.len(),
+                max: MAX_KEY_SIZE,
+            })?
+        }
+        self.writes.insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    /// Commit the writes with a new commit ts,
+    /// fails with `TxnError::Conflict` if any key read by this transaction
+    /// was written by another one committed after `read_ts`.
+    pub fn commit(mut self) -> Result<(), Error> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        self.db.core.stall_writes()?;
+        // Hold the write lock until writes are applied, so that commits are serialized
+        // and become visible in the order of their commit ts.
+        let _write_lock = self.db.core.orc.write_lock();
+        let commit_ts = {
+            let mut orc = self.db.core.orc.lock();
+            if orc.has_conflict(&self) {
+                Err(TxnError::Conflict)?
+            }
+            orc.new_commit_ts(&self)
+        };
+        let writes = ::std::mem::take(&mut self.writes);
+        let res = self.db.write_entries(commit_ts, writes.into_iter().collect());
+        let mut orc = self.db.core.orc.lock();
+        match res {
+            Ok(()) => orc.done_commit(commit_ts),
+            Err(_) => orc.abort_commit(commit_ts),
+        }
+        res
+    }
+
+    pub fn discard(mut self) {
+        self.done();
+    }
+
+    fn done(&mut self) {
+        if !self.done {
+            self.done = true;
+            self.db.core.orc.done_read(self.read_ts);
+        }
+    }
+}
+
+impl<'a> Drop for Txn<'a> {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConfigBuilder;
+
+    fn open_db(dir: &tempdir::TempDir) -> DB {
+        DB::open(&ConfigBuilder::new(dir.path()).build()).unwrap()
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        let mut txn = db.begin(false);
+        txn.set(b"key1", b"value1").unwrap();
+        assert_eq!(Some(b"value1".to_vec()), txn.get(b"key1").unwrap());
+        // not visible before commit.
+        assert_eq!(None, db.get(b"key1").unwrap());
+        txn.delete(b"key1").unwrap();
+        assert_eq!(None, txn.get(b"key1").unwrap());
+        txn.set(b"key1", b"value2").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+    }
+
+    #[test]
+    fn test_snapshot_read() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        db.set(b"key1", b"value1").unwrap();
+        let mut txn = db.begin(true);
+        db.set(b"key1", b"value2").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        assert_eq!(Some(b"value1".to_vec()), txn.get(b"key1").unwrap());
+        assert_eq!(None, txn.get(b"key2").unwrap());
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+    }
+
+    #[test]
+    fn test_read_only() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        let mut txn = db.begin(true);
+        assert!(txn.set(b"key1", b"value1").is_err());
+        assert!(txn.delete(b"key1").is_err());
+    }
+
+    #[test]
+    fn test_key_too_large() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        let key = vec![0; MAX_KEY_SIZE + 1];
+        let err = db.set(&key, b"value").unwrap_err();
+        match err.downcast_ref::<TxnError>() {
+            Some(&TxnError::KeyTooLarge { len, max }) => {
+                assert_eq!((MAX_KEY_SIZE + 1, MAX_KEY_SIZE), (len, max))
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        db.set(&key[1..], b"value").unwrap();
+        assert_eq!(Some(b"value".to_vec()), db.get(&key[1..]).unwrap());
+    }
+
+    #[test]
+    fn test_commit_ts() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        let read_ts = db.begin(true).read_ts();
+        db.set(b"key1", b"value1").unwrap();
+        assert_eq!(read_ts + 1, db.begin(true).read_ts());
+    }
+
+    #[test]
+    fn test_read_during_commit() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        db.set(b"key1", b"value1").unwrap();
+        let read_ts = db.begin(true).read_ts();
+        // a commit applying its writes holds the write lock with its ts handed out.
+        let _write_lock = db.core.orc.write_lock();
+        let mut txn = db.begin(false);
+        txn.set(b"key1", b"value2").unwrap();
+        db.core.orc.lock().new_commit_ts(&txn);
+        // reads neither wait on it nor see it.
+        assert_eq!(read_ts, db.begin(true).read_ts());
+        assert_eq!(Some(b"value1".to_vec()), db.get(b"key1").unwrap());
+    }
+
+    #[test]
+    fn test_conflict() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        db.set(b"counter", b"0").unwrap();
+
+        let mut txn1 = db.begin(false);
+        let mut txn2 = db.begin(false);
+        assert_eq!(Some(b"0".to_vec()), txn1.get(b"counter").unwrap());
+        assert_eq!(Some(b"0".to_vec()), txn2.get(b"counter").unwrap());
+        txn1.set(b"counter", b"1").unwrap();
+        txn2.set(b"counter", b"1").unwrap();
+        txn1.commit().unwrap();
+
+        let err = txn2.commit().unwrap_err();
+        match err.downcast_ref::<TxnError>() {
+            Some(&TxnError::Conflict) => {}
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        assert_eq!(Some(b"1".to_vec()), db.get(b"counter").unwrap());
+    }
+
+    #[test]
+    fn test_no_conflict() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+
+        // blind writes never conflict.
+        let mut txn1 = db.begin(false);
+        let mut txn2 = db.begin(false);
+        txn1.set(b"key1", b"value1").unwrap();
+        txn2.set(b"key1", b"value2").unwrap();
+        txn1.commit().unwrap();
+        txn2.commit().unwrap();
+        assert_eq!(Some(b"value2".to_vec()), db.get(b"key1").unwrap());
+
+        // reads of other keys do not conflict.
+        let mut txn1 = db.begin(false);
+        let mut txn2 = db.begin(false);
+        txn1.get(b"key1").unwrap();
+        txn1.set(b"key2", b"value2").unwrap();
+        txn2.get(b"key3").unwrap();
+        txn2.set(b"key4", b"value4").unwrap();
+        txn1.commit().unwrap();
+        txn2.commit().unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_committed_txns() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let db = open_db(&tmp_dir);
+        let txn = db.begin(false);
+        db.set(b"key1", b"value1").unwrap();
+        db.set(b"key2", b"value2").unwrap();
+        assert_eq!(2, db.core.orc.lock().committed_txns.len());
+        txn.discard();
+        assert_eq!(0, db.core.orc.lock().committed_txns.len());
+    }
+
+    #[test]
+    fn test_failed_commit_no_conflict() {
+        let tmp_dir = tempdir::TempDir::new("txn").unwrap();
+        let cfg = ConfigBuilder::new(tmp_dir.path())
+            .value_log_file_size(1)
+            .build();
+        let db = DB::open(&cfg).unwrap();
+        db.set(b"key1", b"value1").unwrap();
+        let mut txn = db.begin(false);
+        txn.get(b"key1").unwrap();
+
+        // the next write rolls over the value log segment, which fails without the dir.
+        ::std::fs::remove_dir_all(tmp_dir.path()).unwrap();
+        assert!(db.set(b"key1", b"value2").is_err());
+        assert!(db.core.orc.lock().committed_txns.is_empty());
+        assert!(!db.core.orc.lock().has_conflict(&txn));
+    }
 }
@@ -1,23 +1,48 @@
-extern crate bytes;
+// the impls generated by `derive(Fail)` are non-local.
+#![allow(non_local_definitions)]
+
 extern crate tempdir;
-use self::bytes::BytesMut;
 use std::io::Result;
 use std::result::Result as StdResult;
 
-use std::fs::{create_dir_all, read_dir, OpenOptions};
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
 use failure::Error;
 use std::fs::DirEntry;
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
-use std::io::{ErrorKind, Result as IoResult, Seek, SeekFrom, Write};
-use super::structs::{Value, ValuePointer};
+use std::io::{ErrorKind, Result as IoResult, Write};
+use super::structs::{ChecksumMismatch, Value, ValuePointer, BIT_FIN_TXN, BIT_TXN};
+use std::io::Error as IoError;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
 
 use super::segment::LogFile;
+use table::TableLoadMode;
+
+#[derive(Debug, Fail)]
+pub enum ValueLogError {
+    #[fail(display = "Corrupted entry in value log file {} at offset {}", fid, offset)]
+    CorruptedEntry { fid: u32, offset: u32 },
+    #[fail(display = "Checksum mismatch of entry in value log file {} at offset {}: \
+                      expected({:#010x}) actual({:#010x})",
+           fid, offset, expected, actual)]
+    ChecksumMismatch {
+        fid: u32,
+        offset: u32,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+pub const DEFAULT_SEGMENT_MAX_SIZE: u32 = 1024 * 1024 * 128;
 
 pub struct ValueOption {
     dir: String,
     segment_max_size: u32,
     sync: bool,
+    load_mode: TableLoadMode,
 }
 
 impl Default for ValueOption {
@@ -30,17 +55,26 @@ impl Default for ValueOption {
                 .unwrap()
                 .to_string(),
             sync: false,
-            segment_max_size: 1024 * 1024 * 128,
+            segment_max_size: DEFAULT_SEGMENT_MAX_SIZE,
+            load_mode: TableLoadMode::MemoryMap,
         }
     }
 }
 
 impl ValueOption {
-    pub fn new(dir: &Path, segment_max_size: u32, sync: bool) -> ValueOption {
+    /// Readonly segments are memory mapped unless `load_mode` is `TableLoadMode::FileIO`,
+    /// the active segment is always read with positional reads.
+    pub fn new(
+        dir: &Path,
+        segment_max_size: u32,
+        sync: bool,
+        load_mode: TableLoadMode,
+    ) -> ValueOption {
         ValueOption {
             dir: dir.to_str().unwrap().to_string(),
             segment_max_size,
             sync,
+            load_mode,
         }
     }
 }
@@ -50,9 +84,25 @@ pub struct ValueLog {
     dir_path: PathBuf,
     segment_max_size: u32,
     sync: bool,
-    log_files: HashMap<u32, LogFile>,
-    cur_fid: u32,
-    write_buffer: Vec<u8>,
+    load_mode: TableLoadMode,
+    // readers clone the segment out and read it without holding the lock.
+    log_files: RwLock<HashMap<u32, Arc<LogFile>>>,
+    // published after the segment is in `log_files`.
+    cur_fid: AtomicU32,
+    // serializes appends, holds the buffer entries are encoded into.
+    writer: Mutex<Vec<u8>>,
+    // entries before head(included) are persisted in tables.
+    head: Mutex<ValuePointer>,
+    // segments collected by gc, deleted once no reader may use them.
+    pending_deletes: Vec<u32>,
+    discard_stats: Mutex<DiscardStats>,
+}
+
+// bytes of obsolete entries in each segment.
+#[derive(Debug, Default)]
+struct DiscardStats {
+    stats: HashMap<u32, u64>,
+    dirty: bool,
 }
 
 use std::fmt::Display;
@@ -63,7 +113,10 @@ impl Display for ValueLog {
         write!(
             f,
             "value_log(dir: {:?}, segment_max_size: {:?}, sync: {:?}, cur_fid: {:?})",
-            &self.dir_path, self.segment_max_size, self.sync, self.cur_fid
+            self.dir_path,
+            self.segment_max_size,
+            self.sync,
+            self.cur_fid.load(Ordering::Acquire)
         )
     }
 }
@@ -71,18 +124,13 @@ impl Display for ValueLog {
 // Init/open
 impl ValueLog {
     const LOG_SUFFIX: &'static str = "vlog";
+    const HEAD_FILE: &'static str = "HEAD";
+    const DISCARD_FILE: &'static str = "DISCARD";
     fn get_value_log_dir_entry(path: &Path) -> Result<Vec<DirEntry>> {
         let entries: Vec<DirEntry> = read_dir(path)?
-            .fold(Ok(vec![]), |acc, entry| {
-                acc.and_then(|mut v| {
-                    entry.map(|e| {
-                        v.push(e);
-                        v
-                    })
-                })
-            })?
+            .collect::<Result<Vec<DirEntry>>>()?
             .into_iter()
-            .filter(|e| Self::is_log_file(e))
+            .filter(Self::is_log_file)
             .collect();
         Ok(entries)
     }
@@ -109,19 +157,16 @@ impl ValueLog {
         };
 
         // load prev files if any
-        let mut log_files: HashMap<u32, LogFile> = HashMap::with_capacity(prev_fids.len() + 1);
+        let mut log_files: HashMap<u32, Arc<LogFile>> =
+            HashMap::with_capacity(prev_fids.len() + 1);
         for &fid in prev_fids.iter() {
-            let log_path = dir_path.join(&Self::fid_to_pathbuf(fid));
-            let file = OpenOptions::new().read(true).open(&log_path)?;
-            let log_file = LogFile::new(fid, &log_path, file, true)?;
-            log_files.insert(log_file.fid(), log_file);
+            let log_path = dir_path.join(Self::fid_to_pathbuf(fid));
+            let log_file = Self::open_readonly(fid, &log_path, opt.load_mode)?;
+            log_files.insert(log_file.fid(), Arc::new(log_file));
         }
 
         // load or create current log file
-        let cur_fid = match max_fid {
-            Some(fid) => fid,
-            None => 0,
-        };
+        let cur_fid = max_fid.unwrap_or_default();
         let log_path = dir_path.join(Self::fid_to_pathbuf(cur_fid));
         let file = OpenOptions::new()
             .create(true)
@@ -131,18 +176,78 @@ impl ValueLog {
 
         let cur_log_file = LogFile::new(cur_fid, &log_path, file, false)?;
 
-        log_files.insert(cur_log_file.fid(), cur_log_file);
+        log_files.insert(cur_log_file.fid(), Arc::new(cur_log_file));
+
+        let head = Self::read_head(&dir_path)?;
+        let mut stats = Self::read_discard_stats(&dir_path)?;
+        stats.retain(|fid, _| log_files.contains_key(fid));
 
         Ok(ValueLog {
             dir_path,
             segment_max_size: opt.segment_max_size,
             sync: opt.sync,
-            cur_fid,
-            log_files,
-            write_buffer: Vec::with_capacity(1024 * 8),
+            load_mode: opt.load_mode,
+            cur_fid: AtomicU32::new(cur_fid),
+            log_files: RwLock::new(log_files),
+            writer: Mutex::new(Vec::with_capacity(1024 * 8)),
+            head: Mutex::new(head),
+            pending_deletes: vec![],
+            discard_stats: Mutex::new(DiscardStats {
+                stats,
+                dirty: false,
+            }),
         })
     }
 
+    fn open_readonly(fid: u32, log_path: &Path, load_mode: TableLoadMode) -> Result<LogFile> {
+        let file = OpenOptions::new().read(true).open(log_path)?;
+        match load_mode {
+            TableLoadMode::FileIO => LogFile::new(fid, log_path, file, true),
+            TableLoadMode::LoadToRAM | TableLoadMode::MemoryMap => {
+                LogFile::new_mmap(fid, log_path, file)
+            }
+        }
+    }
+
+    fn read_head(dir_path: &Path) -> Result<ValuePointer> {
+        match File::open(dir_path.join(Self::HEAD_FILE)) {
+            Ok(mut f) => ValuePointer::decode(&mut f),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(ValuePointer::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read_discard_stats(dir_path: &Path) -> Result<HashMap<u32, u64>> {
+        let mut f = match File::open(dir_path.join(Self::DISCARD_FILE)) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+        let count = f.read_u32::<BigEndian>()?;
+        let mut stats = HashMap::with_capacity(count as usize);
+        for _ in 0..count {
+            let fid = f.read_u32::<BigEndian>()?;
+            let discarded = f.read_u64::<BigEndian>()?;
+            stats.insert(fid, discarded);
+        }
+        Ok(stats)
+    }
+
+    // Write a file under the value log dir by renaming a synced temp file,
+    // so that either the old or the new content is seen after a crash.
+    fn write_file<F>(&self, name: &str, f: F) -> IoResult<()>
+    where
+        F: FnOnce(&mut File) -> IoResult<()>,
+    {
+        let tmp_path = self.dir_path.join(name).with_extension("tmp");
+        {
+            let mut file = File::create(&tmp_path)?;
+            f(&mut file)?;
+            file.sync_all()?;
+        }
+        rename(&tmp_path, self.dir_path.join(name))
+    }
+
     fn is_log_file(entry: &DirEntry) -> bool {
         let path = entry.path();
         path.is_file()
@@ -172,88 +277,414 @@ impl ValueLog {
         self.active_segment().and_then(|s| s.write_offset())
     }
 
-    pub fn active_segment_mut(&mut self) -> Option<&mut LogFile> {
-        self.log_files.get_mut(&self.cur_fid)
-    }
-    pub fn active_segment(&self) -> Option<&LogFile> {
-        self.log_files.get(&self.cur_fid)
+    pub fn active_segment(&self) -> Option<Arc<LogFile>> {
+        let cur_fid = self.cur_fid.load(Ordering::Acquire);
+        self.log_files.read().unwrap().get(&cur_fid).cloned()
     }
 
-    pub fn write(&mut self, entries: &[Value]) -> IoResult<Vec<ValuePointer>> {
+    /// Append `entries` to the active segment.
+    /// Appends are serialized by their own lock, reads don't wait on them.
+    pub fn write(&self, entries: &[Value]) -> IoResult<Vec<ValuePointer>> {
+        let mut write_buffer = self.writer.lock().unwrap();
         self.rollover_if_necessary()?;
         // TODO: shrunk buffer ?
-        self.write_buffer.clear();
+        write_buffer.clear();
         let mut value_pointers = Vec::with_capacity(entries.len());
 
-        {
-            let mut cur_offset: u32 = self.active_segment()
-                .and_then(|s| s.write_offset())
-                .unwrap();
-            for entry in entries {
-                let len = entry.encode(&mut self.write_buffer)?;
-                value_pointers.push(ValuePointer::new(self.cur_fid, cur_offset, len));
-                cur_offset += len;
-            }
-            self.write_buffer.flush()?;
+        let segment = self.active_segment().unwrap();
+        let mut cur_offset: u32 = segment.write_offset().unwrap();
+        for entry in entries {
+            let len = entry.encode(&mut *write_buffer)?;
+            value_pointers.push(ValuePointer::new(segment.fid(), cur_offset, len));
+            cur_offset += len;
         }
 
         // write all entries.
-        self.internal_write()?;
+        segment.write_bytes(&write_buffer, self.sync)?;
+        write_buffer.clear();
 
         Ok(value_pointers)
     }
 
-    fn internal_write(&mut self) -> IoResult<()> {
-        let segment = self.log_files.get_mut(&self.cur_fid).unwrap();
-        segment.write_bytes(&self.write_buffer, self.sync)?;
-        self.write_buffer.clear();
-        Ok(())
-    }
-
     fn should_rollover(&self) -> bool {
         let cur_write_offset = self.active_segment().unwrap().write_offset().unwrap();
         cur_write_offset >= self.segment_max_size
     }
-    fn rollover_if_necessary(&mut self) -> IoResult<()> {
-        use std::mem::drop;
+
+    // Called with the writer lock held.
+    fn rollover_if_necessary(&self) -> IoResult<()> {
         if self.should_rollover() {
-            let segment = self.log_files.remove(&self.cur_fid).unwrap();
-            let fp = segment.file_path().to_path_buf();
-            drop(segment);
+            self.persist_discard_stats()?;
+            let cur_fid = self.cur_fid.load(Ordering::Acquire);
             // reopen in readonly mode
-            let file = OpenOptions::new().read(true).open(&fp)?;
-            let segment = LogFile::new(self.cur_fid, &fp, file, true)?;
-            self.log_files.insert(self.cur_fid, segment);
+            let fp = self.dir_path.join(Self::fid_to_pathbuf(cur_fid));
+            let segment = Self::open_readonly(cur_fid, &fp, self.load_mode)?;
 
-            self.cur_fid += 1;
             // create new segment
-            let rollover_path = self.dir_path.join(Self::fid_to_pathbuf(self.cur_fid));
+            let rollover_fid = cur_fid + 1;
+            let rollover_path = self.dir_path.join(Self::fid_to_pathbuf(rollover_fid));
             let rollover_file = OpenOptions::new()
                 .create_new(true)
                 .read(true)
                 .append(true)
                 .open(&rollover_path)?;
             let rollover_segment =
-                LogFile::new(self.cur_fid, &rollover_path, rollover_file, false)?;
-            self.log_files.insert(self.cur_fid, rollover_segment);
+                LogFile::new(rollover_fid, &rollover_path, rollover_file, false)?;
+
+            let mut log_files = self.log_files.write().unwrap();
+            log_files.insert(cur_fid, Arc::new(segment));
+            log_files.insert(rollover_fid, Arc::new(rollover_segment));
+            self.cur_fid.store(rollover_fid, Ordering::Release);
         }
 
         Ok(())
     }
 }
 
-// Impl read related ops
+// Impl head and replay
+impl ValueLog {
+    /// The last pointer whose entry is persisted in tables.
+    pub fn head(&self) -> ValuePointer {
+        *self.head.lock().unwrap()
+    }
+
+    /// Persist the head, entries before it will not be replayed on next open.
+    pub fn set_head(&self, head: ValuePointer) -> IoResult<()> {
+        let mut cur_head = self.head.lock().unwrap();
+        self.write_file(Self::HEAD_FILE, |f| head.encode(f).map(|_| ()))?;
+        *cur_head = head;
+        Ok(())
+    }
+
+    /// Call `f` with each entry after head in write order, along with its pointer.
+    /// Entries of a transaction are passed only if the whole transaction is found.
+    /// A torn or corrupted last entry of the active segment is truncated,
+    /// while any other corrupted entry is an error.
+    pub fn replay<F>(&mut self, mut f: F) -> StdResult<(), Error>
+    where
+        F: FnMut(Value, ValuePointer) -> StdResult<(), Error>,
+    {
+        let head = self.head();
+        let cur_fid = self.cur_fid.load(Ordering::Acquire);
+        let log_files = self.log_files.read().unwrap();
+        let mut fids: Vec<u32> = log_files
+            .keys()
+            .cloned()
+            .filter(|&fid| fid >= head.fid())
+            .collect();
+        fids.sort();
+        for fid in fids {
+            let start = if fid == head.fid() {
+                head.offset() + head.len()
+            } else {
+                0
+            };
+            let segment = &log_files[&fid];
+            let size = segment.size()?;
+            let end = Self::replay_segment(segment, start, size, &mut f)?;
+            if end < size {
+                if fid != cur_fid {
+                    Err(ValueLogError::CorruptedEntry { fid, offset: end })?
+                }
+                segment.truncate(end)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Replay entries in `segment` between `start` and `size`,
+    // return the end offset of the last valid entry.
+    fn replay_segment<F>(
+        segment: &LogFile,
+        start: u32,
+        size: u32,
+        f: &mut F,
+    ) -> StdResult<u32, Error>
+    where
+        F: FnMut(Value, ValuePointer) -> StdResult<(), Error>,
+    {
+        if start >= size {
+            return Ok(start);
+        }
+        let buf = segment.read_bytes(start, size - start)?;
+        let mut reader: &[u8] = &buf;
+        let mut offset = start;
+        let mut valid_end = start;
+        let mut txn_entries = vec![];
+        while !reader.is_empty() {
+            let value = match Value::decode(&mut reader) {
+                Ok(v) => v,
+                Err(ref e) if Self::is_torn_entry(e) => {
+                    // only the last entry can be torn, a bad one followed by valid ones is corrupted.
+                    if e.downcast_ref::<ChecksumMismatch>().is_some()
+                        && Value::decode(&mut reader).is_ok()
+                    {
+                        Err(ValueLogError::CorruptedEntry {
+                            fid: segment.fid(),
+                            offset,
+                        })?
+                    }
+                    break;
+                }
+                Err(e) => Err(e)?,
+            };
+            let len = size - offset - reader.len() as u32;
+            let pointer = ValuePointer::new(segment.fid(), offset, len);
+            offset += len;
+
+            if value.meta & BIT_FIN_TXN != 0 {
+                for (v, p) in txn_entries.drain(..) {
+                    f(v, p)?;
+                }
+                valid_end = offset;
+            } else if value.meta & BIT_TXN != 0 {
+                txn_entries.push((value, pointer));
+            } else {
+                f(value, pointer)?;
+                valid_end = offset;
+            }
+        }
+        Ok(valid_end)
+    }
+
+    // An entry partially written or with mismatched checksum.
+    fn is_torn_entry(e: &Error) -> bool {
+        e.downcast_ref::<ChecksumMismatch>().is_some()
+            || e.downcast_ref::<IoError>()
+                .filter(|e| e.kind() == ErrorKind::UnexpectedEof)
+                .is_some()
+    }
+}
+
+/// What gc does with an entry of the segment being collected.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GcAction {
+    /// The entry is obsolete and dropped along with the segment.
+    Discard,
+    /// The entry is still needed and rewritten to the active segment.
+    Rewrite,
+    /// The entry is still needed but can't be moved now, so the segment is kept.
+    Keep,
+}
+
+// Impl gc
+impl ValueLog {
+    // Max size of entries rewritten in one batch during gc.
+    const GC_BATCH_SIZE: usize = 1024 * 1024;
+
+    /// Collect a segment if at least `discard_ratio` of its bytes are stale,
+    /// returns whether a segment is collected.
+    ///
+    /// Only segments before the head are candidates, others are still needed for replay.
+    /// `action` tells what to do with the entry at the pointer, see `GcAction`.
+    /// Entries to rewrite are appended to the active segment,
+    /// then `update` is called to point the LSM tree at their new places.
+    /// The collected segment is deleted by `delete_pending_segments`.
+    pub fn run_gc<F, U>(
+        &mut self,
+        discard_ratio: f64,
+        mut action: F,
+        mut update: U,
+    ) -> StdResult<bool, Error>
+    where
+        F: FnMut(&Value, &ValuePointer) -> StdResult<GcAction, Error>,
+        U: FnMut(&[Value], &[ValuePointer]) -> StdResult<(), Error>,
+    {
+        let fid = match self.pick_gc_candidate() {
+            Some(fid) => fid,
+            None => return Ok(false),
+        };
+
+        let mut live_entries = vec![];
+        let mut total_size = 0u64;
+        let mut live_size = 0u64;
+        {
+            let segment = self.log_files.read().unwrap()[&fid].clone();
+            let size = segment.size()?;
+            let buf = segment.read_bytes(0, size)?;
+            let mut reader: &[u8] = &buf;
+            while !reader.is_empty() {
+                let offset = size - reader.len() as u32;
+                let value = Value::decode(&mut reader)?;
+                let pointer = ValuePointer::new(fid, offset, size - offset - reader.len() as u32);
+                total_size += u64::from(pointer.len());
+                if value.meta & BIT_FIN_TXN != 0 {
+                    continue;
+                }
+                match action(&value, &pointer)? {
+                    GcAction::Discard => {}
+                    GcAction::Rewrite => {
+                        live_size += u64::from(pointer.len());
+                        live_entries.push(value);
+                    }
+                    GcAction::Keep => return Ok(false),
+                }
+            }
+        }
+        let discarded = (total_size - live_size) as f64;
+        if total_size == 0 || discarded < discard_ratio * total_size as f64 {
+            return Ok(false);
+        }
+
+        let mut batch = vec![];
+        let mut batch_size = 0;
+        for mut value in live_entries {
+            // rewritten entries are replayed one by one.
+            value.meta &= !BIT_TXN;
+            batch_size += value.key.len() + value.value.len();
+            batch.push(value);
+            if batch_size >= Self::GC_BATCH_SIZE {
+                let pointers = self.write(&batch)?;
+                update(&batch, &pointers)?;
+                batch.clear();
+                batch_size = 0;
+            }
+        }
+        if !batch.is_empty() {
+            let pointers = self.write(&batch)?;
+            update(&batch, &pointers)?;
+        }
+
+        self.pending_deletes.push(fid);
+        {
+            let mut discard_stats = self.discard_stats.lock().unwrap();
+            if discard_stats.stats.remove(&fid).is_some() {
+                discard_stats.dirty = true;
+            }
+        }
+        self.persist_discard_stats()?;
+        Ok(true)
+    }
+
+    // The read-only segment before head with the most discarded bytes,
+    // or the oldest one if nothing is discarded in them.
+    fn pick_gc_candidate(&self) -> Option<u32> {
+        let head_fid = self.head().fid();
+        let cur_fid = self.cur_fid.load(Ordering::Acquire);
+        let is_candidate =
+            |fid: u32| fid < head_fid && fid != cur_fid && !self.pending_deletes.contains(&fid);
+        self.ranked_discard_stats()
+            .into_iter()
+            .map(|(fid, _)| fid)
+            .find(|&fid| is_candidate(fid))
+            .or_else(|| {
+                self.log_files
+                    .read()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .filter(|&fid| is_candidate(fid))
+                    .min()
+            })
+    }
+
+    /// Delete segments collected by gc,
+    /// the caller should make sure that no reader may still read them.
+    pub fn delete_pending_segments(&mut self) -> IoResult<()> {
+        while let Some(fid) = self.pending_deletes.pop() {
+            if let Some(segment) = self.log_files.get_mut().unwrap().remove(&fid) {
+                let path = segment.file_path().to_path_buf();
+                drop(segment);
+                remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_pending_deletes(&self) -> bool {
+        !self.pending_deletes.is_empty()
+    }
+}
+
+// Impl discard stats
 impl ValueLog {
-    pub fn read(&mut self, pointer: &ValuePointer) -> IoResult<Value> {
-        if pointer.fid() == self.cur_fid && pointer.offset() >= self.write_offset().unwrap() {
-            Err(ErrorKind::UnexpectedEof)?
+    /// Record entries at `pointers` as obsolete,
+    /// e.g. they are overwritten or dropped by compaction.
+    /// Stats are persisted on rollover, after gc or by `persist_discard_stats`.
+    pub fn update_discard_stats(&self, pointers: &[ValuePointer]) {
+        let mut discard_stats = self.discard_stats.lock().unwrap();
+        for p in pointers.iter().filter(|p| !p.is_empty()) {
+            *discard_stats.stats.entry(p.fid()).or_insert(0) += u64::from(p.len());
+            discard_stats.dirty = true;
+        }
+    }
+
+    /// Segments and their discarded bytes, the one with the most garbage first.
+    pub fn ranked_discard_stats(&self) -> Vec<(u32, u64)> {
+        let mut stats: Vec<(u32, u64)> = self.discard_stats
+            .lock()
+            .unwrap()
+            .stats
+            .iter()
+            .filter(|&(_, &discarded)| discarded > 0)
+            .map(|(&fid, &discarded)| (fid, discarded))
+            .collect();
+        stats.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        stats
+    }
+
+    pub fn persist_discard_stats(&self) -> IoResult<()> {
+        let mut discard_stats = self.discard_stats.lock().unwrap();
+        if !discard_stats.dirty {
+            return Ok(());
+        }
+        let mut buf = Vec::with_capacity(4 + 12 * discard_stats.stats.len());
+        buf.write_u32::<BigEndian>(discard_stats.stats.len() as u32)?;
+        for (&fid, &discarded) in discard_stats.stats.iter() {
+            buf.write_u32::<BigEndian>(fid)?;
+            buf.write_u64::<BigEndian>(discarded)?;
         }
-        match self.log_files.get_mut(&pointer.fid()) {
+        self.write_file(Self::DISCARD_FILE, |f| f.write_all(&buf))?;
+        discard_stats.dirty = false;
+        Ok(())
+    }
+}
+
+impl Drop for ValueLog {
+    fn drop(&mut self) {
+        // stats are only hints for gc, losing them is harmless.
+        let _ = self.persist_discard_stats();
+    }
+}
+
+// Impl read related ops
+impl ValueLog {
+    /// Read the entry at `pointer`,
+    /// fails with `ValueLogError::ChecksumMismatch` if the entry is corrupted.
+    /// It only takes `&self`, so entries can be read by many threads at the same time.
+    pub fn read(&self, pointer: &ValuePointer) -> StdResult<Value, Error> {
+        let segment = self.log_files.read().unwrap().get(&pointer.fid()).cloned();
+        match segment {
             Some(segment) => {
+                // entries beyond the published offset of the active segment may be partially written.
+                if segment
+                    .write_offset()
+                    .filter(|&end| pointer.offset() >= end)
+                    .is_some()
+                {
+                    Err(IoError::from(ErrorKind::UnexpectedEof))?
+                }
                 let mut buf: &[u8] = &segment.read_bytes(pointer.offset(), pointer.len())?;
-                Value::decode(&mut buf)
+                Value::decode(&mut buf).map_err(|e| {
+                    if let Some(m) = e.downcast_ref::<ChecksumMismatch>() {
+                        return ValueLogError::ChecksumMismatch {
+                            fid: pointer.fid(),
+                            offset: pointer.offset(),
+                            expected: m.expected,
+                            actual: m.actual,
+                        }.into();
+                    }
+                    // the entry doesn't fit in the pointer, its header is corrupted.
+                    if Self::is_torn_entry(&e) {
+                        return ValueLogError::CorruptedEntry {
+                            fid: pointer.fid(),
+                            offset: pointer.offset(),
+                        }.into();
+                    }
+                    e
+                })
             }
-            None => Err(ErrorKind::UnexpectedEof)?,
+            None => Err(IoError::from(ErrorKind::UnexpectedEof))?,
         }
     }
 }
@@ -276,9 +707,9 @@ mod tests {
             ..Default::default()
         });
 
-        assert!(vl.is_ok(), format!("{:?}", vl.err()));
+        assert!(vl.is_ok(), "{:?}", vl.err());
         let vlog = vl.unwrap();
-        assert_eq!(vlog.log_files.len(), 2);
+        assert_eq!(vlog.log_files.read().unwrap().len(), 2);
     }
 
     #[test]
@@ -317,7 +748,7 @@ mod tests {
     #[test]
     fn test_write_entries() {
         let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
-        let mut vl = ValueLog::open(&ValueOption {
+        let vl = ValueLog::open(&ValueOption {
             dir: tmp_dir.path().to_str().unwrap().to_string(),
             ..Default::default()
         }).unwrap();
@@ -330,7 +761,7 @@ mod tests {
     fn test_write_rollover() {
         // max segment size set to 32, insert kv, with size 8, 8 + 4 + 4 + 4 = 20.
         let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
-        let mut vl = ValueLog::open(&ValueOption {
+        let vl = ValueLog::open(&ValueOption {
             dir: tmp_dir.path().to_str().unwrap().to_string(),
             segment_max_size: 32,
             ..Default::default()
@@ -357,7 +788,7 @@ mod tests {
     #[test]
     fn test_read_and_write() {
         let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
-        let mut vl = ValueLog::open(&ValueOption {
+        let vl = ValueLog::open(&ValueOption {
             dir: tmp_dir.path().to_str().unwrap().to_string(),
             segment_max_size: 32,
             ..Default::default()
@@ -385,16 +816,269 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parallel_reads() {
+        for &mode in &[TableLoadMode::MemoryMap, TableLoadMode::FileIO] {
+            let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+            let opt = ValueOption {
+                dir: tmp_dir.path().to_str().unwrap().to_string(),
+                segment_max_size: 256,
+                load_mode: mode,
+                ..Default::default()
+            };
+            let mut ents = vec![];
+            let mut pointers = vec![];
+            {
+                let vl = ValueLog::open(&opt).unwrap();
+                for i in 0..50 {
+                    let ent = Value::new(format!("key{}", i).as_bytes(), b"value");
+                    pointers.extend(vl.write(::std::slice::from_ref(&ent)).unwrap());
+                    ents.push(ent);
+                }
+            }
+            // segments before the last one are readonly after reopen.
+            let vl = ValueLog::open(&opt).unwrap();
+            assert!(vl.log_files.read().unwrap().len() > 2);
+            ::std::thread::scope(|s| {
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        for (ent, p) in ents.iter().zip(pointers.iter()) {
+                            assert_eq!(*ent, vl.read(p).unwrap());
+                        }
+                    });
+                }
+            });
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod replay_tests {
+    use super::*;
+
+    fn open(dir: &Path, segment_max_size: u32) -> ValueLog {
+        ValueLog::open(&ValueOption {
+            dir: dir.to_str().unwrap().to_string(),
+            segment_max_size,
+            ..Default::default()
+        }).unwrap()
+    }
+
+    fn replay_all(vl: &mut ValueLog) -> Vec<(Value, ValuePointer)> {
+        let mut replayed = vec![];
+        vl.replay(|v, p| {
+            replayed.push((v, p));
+            Ok(())
+        }).unwrap();
+        replayed
+    }
+
+    fn txn(entries: &[Value]) -> Vec<Value> {
+        let mut entries: Vec<Value> = entries
+            .iter()
+            .cloned()
+            .map(|mut v| {
+                v.meta |= BIT_TXN;
+                v
+            })
+            .collect();
+        let mut fin = Value::new(b"txn", b"");
+        fin.meta = BIT_FIN_TXN;
+        entries.push(fin);
+        entries
+    }
+
+    #[test]
+    fn test_replay() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let ents = [Value::new(b"1", b"1"), Value::new(b"2", b"2")];
+        let pointers = {
+            let vl = open(tmp_dir.path(), 32);
+            let mut pointers = vl.write(&ents[0..1]).unwrap();
+            pointers.extend(vl.write(&txn(&ents[1..2])).unwrap());
+            pointers
+        };
+        let mut vl = open(tmp_dir.path(), 32);
+        assert_eq!(
+            vec![
+                (ents[0].clone(), pointers[0]),
+                (txn(&ents[1..2])[0].clone(), pointers[1]),
+            ],
+            replay_all(&mut vl)
+        );
+    }
+
+    #[test]
+    fn test_replay_after_head() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let ents = [
+            Value::new(b"11", b"222222"),
+            Value::new(b"22", b"333333"),
+            Value::new(b"33", b"444444"),
+        ];
+        {
+            let vl = open(tmp_dir.path(), 32);
+            let pointers = vl.write(&ents[0..2]).unwrap();
+            // rollover
+            vl.write(&ents[2..3]).unwrap();
+            vl.set_head(pointers[0]).unwrap();
+        }
+        let mut vl = open(tmp_dir.path(), 32);
+        let replayed: Vec<Value> = replay_all(&mut vl).into_iter().map(|r| r.0).collect();
+        assert_eq!(&ents[1..], &replayed[..]);
+    }
+
+    #[test]
+    fn test_replay_truncate_corrupted_tail() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let ents = [Value::new(b"1", b"1"), Value::new(b"2", b"2")];
+        let pointers = open(tmp_dir.path(), 1024).write(&ents).unwrap();
+        let log_path = tmp_dir.path().join("000000.vlog");
+        // flip a bit in the value of the last entry.
+        let mut buf = ::std::fs::read(&log_path).unwrap();
+        let pos = buf.len() - 5;
+        buf[pos] ^= 1;
+        ::std::fs::write(&log_path, &buf).unwrap();
+
+        let mut vl = open(tmp_dir.path(), 1024);
+        assert_eq!(vec![(ents[0].clone(), pointers[0])], replay_all(&mut vl));
+        assert_eq!(Some(pointers[1].offset()), vl.write_offset());
+        assert_eq!(
+            u64::from(pointers[1].offset()),
+            ::std::fs::metadata(&log_path).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_replay_corrupted_middle_entry() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let ents = [
+            Value::new(b"1", b"1"),
+            Value::new(b"2", b"2"),
+            Value::new(b"3", b"3"),
+        ];
+        let pointers = open(tmp_dir.path(), 1024).write(&ents).unwrap();
+        let log_path = tmp_dir.path().join("000000.vlog");
+        // flip a bit in the value of the second entry.
+        let mut buf = ::std::fs::read(&log_path).unwrap();
+        let pos = (pointers[1].offset() + pointers[1].len()) as usize - 5;
+        buf[pos] ^= 1;
+        ::std::fs::write(&log_path, &buf).unwrap();
+
+        let mut vl = open(tmp_dir.path(), 1024);
+        let err = vl.replay(|_, _| Ok(())).unwrap_err();
+        match err.downcast_ref::<ValueLogError>() {
+            Some(&ValueLogError::CorruptedEntry { fid, offset }) => {
+                assert_eq!((0, pointers[1].offset()), (fid, offset))
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        // nothing is truncated.
+        assert_eq!(buf.len() as u64, ::std::fs::metadata(&log_path).unwrap().len());
+    }
+
+    #[test]
+    fn test_replay_drop_incomplete_txn() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let ents = [Value::new(b"1", b"1"), Value::new(b"2", b"2")];
+        let pointers = {
+            let vl = open(tmp_dir.path(), 1024);
+            vl.write(&ents[0..1]).unwrap();
+            // the marker of the transaction is missing.
+            let mut v = ents[1].clone();
+            v.meta |= BIT_TXN;
+            vl.write(&[v]).unwrap()
+        };
+        let mut vl = open(tmp_dir.path(), 1024);
+        let replayed: Vec<Value> = replay_all(&mut vl).into_iter().map(|r| r.0).collect();
+        assert_eq!(&ents[0..1], &replayed[..]);
+        assert_eq!(Some(pointers[0].offset()), vl.write_offset());
+    }
+
+    #[test]
+    fn test_replay_corrupted_readonly_segment() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        {
+            let vl = open(tmp_dir.path(), 32);
+            vl.write(&vec![Value::new(b"11", b"222222"); 2]).unwrap();
+            vl.write(&[Value::new(b"11", b"222222")]).unwrap();
+        }
+        let log_path = tmp_dir.path().join("000000.vlog");
+        let mut buf = ::std::fs::read(&log_path).unwrap();
+        buf[10] ^= 1;
+        ::std::fs::write(&log_path, &buf).unwrap();
+
+        let mut vl = open(tmp_dir.path(), 32);
+        assert!(vl.replay(|_, _| Ok(())).is_err());
+    }
 }
 
 #[cfg(test)]
 mod read_tests {
     use super::*;
 
+    #[test]
+    fn test_read_corrupted_value() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let vl = ValueLog::open(&ValueOption {
+            dir: tmp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }).unwrap();
+        let ents = vec![Value::new(b"11", b"222222"), Value::new(b"22", b"333333")];
+        let pointers = vl.write(&ents).unwrap();
+
+        let log_path = tmp_dir.path().join("000000.vlog");
+        let mut buf = ::std::fs::read(&log_path).unwrap();
+        let pos = (pointers[1].offset() + pointers[1].len()) as usize - 5;
+        buf[pos] ^= 1;
+        ::std::fs::write(&log_path, &buf).unwrap();
+
+        assert_eq!(ents[0], vl.read(&pointers[0]).unwrap());
+        let err = vl.read(&pointers[1]).unwrap_err();
+        match err.downcast_ref::<ValueLogError>() {
+            Some(&ValueLogError::ChecksumMismatch {
+                fid,
+                offset,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(pointers[1].fid(), fid);
+                assert_eq!(pointers[1].offset(), offset);
+                assert_ne!(expected, actual);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_read_corrupted_header() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let vl = ValueLog::open(&ValueOption {
+            dir: tmp_dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }).unwrap();
+        let pointers = vl.write(&[Value::new(b"11", b"222222")]).unwrap();
+
+        // the value length is about 4GB.
+        let log_path = tmp_dir.path().join("000000.vlog");
+        let mut buf = ::std::fs::read(&log_path).unwrap();
+        buf[pointers[0].offset() as usize + 4] = 0xff;
+        ::std::fs::write(&log_path, &buf).unwrap();
+
+        let err = vl.read(&pointers[0]).unwrap_err();
+        match err.downcast_ref::<ValueLogError>() {
+            Some(&ValueLogError::CorruptedEntry { fid, offset }) => {
+                assert_eq!((pointers[0].fid(), pointers[0].offset()), (fid, offset))
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+    }
+
     #[test]
     fn test_read_value() {
         let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
-        let mut vl = ValueLog::open(&ValueOption {
+        let vl = ValueLog::open(&ValueOption {
             dir: tmp_dir.path().to_str().unwrap().to_string(),
             segment_max_size: 32,
             ..Default::default()
@@ -407,4 +1091,155 @@ mod read_tests {
             assert_eq!(value.unwrap(), ents[i]);
         }
     }
+
+    #[test]
+    fn test_read_during_append() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let vl = ValueLog::open(&ValueOption {
+            dir: tmp_dir.path().to_str().unwrap().to_string(),
+            segment_max_size: 64,
+            ..Default::default()
+        }).unwrap();
+        let vl = &vl;
+        let (tx, rx) = ::std::sync::mpsc::channel();
+        ::std::thread::scope(|s| {
+            // reads don't wait on the append lock.
+            {
+                let _writer = vl.writer.lock().unwrap();
+                s.spawn(|| assert!(vl.read(&ValuePointer::new(0, 0, 1)).is_err()))
+                    .join()
+                    .unwrap();
+            }
+            s.spawn(move || {
+                for i in 0..100 {
+                    let ent = Value::new(format!("key{}", i).as_bytes(), b"value");
+                    let p = vl.write(::std::slice::from_ref(&ent)).unwrap();
+                    tx.send((ent, p[0])).unwrap();
+                }
+            });
+            // entries are readable once their pointers are returned, even across rollovers.
+            for (ent, p) in rx {
+                assert_eq!(ent, vl.read(&p).unwrap());
+            }
+        });
+        assert!(vl.log_files.read().unwrap().len() > 2);
+    }
+}
+
+#[cfg(test)]
+mod gc_tests {
+    use super::*;
+
+    #[test]
+    fn test_run_gc() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let mut vl = ValueLog::open(&ValueOption {
+            dir: tmp_dir.path().to_str().unwrap().to_string(),
+            segment_max_size: 32,
+            ..Default::default()
+        }).unwrap();
+        let ents = vec![Value::new(b"11", b"222222"), Value::new(b"22", b"333333")];
+        let pointers = vl.write(&ents).unwrap();
+        // no segment before head.
+        assert!(!vl.run_gc(0.0, |_, _| Ok(GcAction::Rewrite), |_, _| Ok(())).unwrap());
+
+        let head = vl.write(&[Value::new(b"33", b"444444")]).unwrap()[0];
+        vl.set_head(head).unwrap();
+        assert!(!vl.run_gc(0.5, |_, _| Ok(GcAction::Rewrite), |_, _| Ok(())).unwrap());
+        // an entry which can't be moved keeps the segment.
+        let keep = |_: &Value, p: &ValuePointer| {
+            Ok(if *p == pointers[1] {
+                GcAction::Keep
+            } else {
+                GcAction::Discard
+            })
+        };
+        assert!(!vl.run_gc(0.0, keep, |_, _| Ok(())).unwrap());
+        assert!(tmp_dir.path().join("000000.vlog").exists());
+        assert!(!vl.has_pending_deletes());
+
+        let mut rewritten = vec![];
+        let collected = vl.run_gc(
+            0.5,
+            |_, p| {
+                Ok(if *p == pointers[1] {
+                    GcAction::Rewrite
+                } else {
+                    GcAction::Discard
+                })
+            },
+            |values, ptrs| {
+                rewritten.extend(values.iter().cloned().zip(ptrs.iter().cloned()));
+                Ok(())
+            },
+        );
+        assert!(collected.unwrap());
+        assert_eq!(1, rewritten.len());
+        assert_eq!(ents[1], rewritten[0].0);
+        assert_eq!(ents[1], vl.read(&rewritten[0].1).unwrap());
+        // only deleted once readers are done.
+        let log_path = tmp_dir.path().join("000000.vlog");
+        assert!(log_path.exists());
+        vl.delete_pending_segments().unwrap();
+        assert!(!log_path.exists());
+    }
+}
+
+#[cfg(test)]
+mod discard_tests {
+    use super::*;
+
+    fn open(dir: &Path) -> ValueLog {
+        ValueLog::open(&ValueOption {
+            dir: dir.to_str().unwrap().to_string(),
+            segment_max_size: 32,
+            ..Default::default()
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_discard_stats() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let mut pointers = vec![];
+        {
+            let vl = open(tmp_dir.path());
+            for _ in 0..3 {
+                pointers.extend(vl.write(&vec![Value::new(b"11", b"222222"); 2]).unwrap());
+            }
+            vl.update_discard_stats(&pointers[0..1]);
+            vl.update_discard_stats(&pointers[2..4]);
+            vl.update_discard_stats(&[ValuePointer::default()]);
+            let (p0, p2) = (u64::from(pointers[0].len()), u64::from(pointers[2].len()));
+            assert_eq!(vec![(1, p2 * 2), (0, p0)], vl.ranked_discard_stats());
+            vl.persist_discard_stats().unwrap();
+        }
+        // stats are reloaded, and the ones of deleted segments are dropped.
+        remove_file(tmp_dir.path().join("000000.vlog")).unwrap();
+        let vl = open(tmp_dir.path());
+        assert_eq!(
+            vec![(1, u64::from(pointers[2].len()) * 2)],
+            vl.ranked_discard_stats()
+        );
+    }
+
+    #[test]
+    fn test_gc_pick_most_discarded() {
+        let tmp_dir = tempdir::TempDir::new("value_log").unwrap();
+        let mut vl = open(tmp_dir.path());
+        let mut pointers = vec![];
+        for _ in 0..3 {
+            pointers.extend(vl.write(&vec![Value::new(b"11", b"222222"); 2]).unwrap());
+        }
+        vl.set_head(pointers[5]).unwrap();
+        vl.update_discard_stats(&pointers[2..4]);
+
+        let mut scanned = vec![];
+        let action = |_: &Value, p: &ValuePointer| {
+            scanned.push(p.fid());
+            Ok(GcAction::Discard)
+        };
+        assert!(vl.run_gc(0.5, action, |_, _| Ok(())).unwrap());
+        assert_eq!(vec![1, 1], scanned);
+        assert!(vl.ranked_discard_stats().is_empty());
+    }
 }
@@ -1,7 +1,11 @@
+use memmap::{Mmap, MmapOptions};
+use std::borrow::Cow;
 use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io::{Result, Seek, SeekFrom};
-use std::io::{Read, Result as IoResult, Write};
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 #[derive(Debug)]
 pub struct LogFile {
@@ -9,7 +13,10 @@ pub struct LogFile {
     file_path: PathBuf,
     file: File,
     readonly: bool,
-    write_offset: u32,
+    // published after the bytes before it are written, so readers never see a torn entry.
+    write_offset: AtomicU32,
+    // the whole file mapped in memory, only for readonly segments.
+    mmap: Option<Mmap>,
 }
 
 impl LogFile {
@@ -19,11 +26,23 @@ impl LogFile {
             file_path: file_path.to_path_buf(),
             file,
             readonly,
-            write_offset: 0,
+            write_offset: AtomicU32::new(0),
+            mmap: None,
         };
         if !readonly {
             // TODO: make sure that the file is not exceed 4GB, or else the u64 -> u32 will cause error.
-            f.write_offset = f.file.seek(SeekFrom::End(0))? as u32;
+            let end = f.file.seek(SeekFrom::End(0))? as u32;
+            f.write_offset.store(end, Ordering::Release);
+        }
+        Ok(f)
+    }
+
+    /// Open a readonly segment which is read through a memory map.
+    pub fn new_mmap(fid: u32, file_path: &Path, file: File) -> Result<LogFile> {
+        let mut f = LogFile::new(fid, file_path, file, true)?;
+        // an empty file can't be mapped, and there is nothing to read in it.
+        if f.size()? > 0 {
+            f.mmap = Some(unsafe { MmapOptions::new().map(&f.file) }?);
         }
         Ok(f)
     }
@@ -38,35 +57,58 @@ impl LogFile {
         &self.file_path
     }
 
+    // size of the file on disk.
+    pub fn size(&self) -> Result<u32> {
+        Ok(self.file.metadata()?.len() as u32)
+    }
+
     // current write offset
     #[inline]
     pub fn write_offset(&self) -> Option<u32> {
         if self.readonly {
             None
         } else {
-            Some(self.write_offset)
+            Some(self.write_offset.load(Ordering::Acquire))
         }
     }
 }
 
 impl LogFile {
-    pub fn read_bytes(&mut self, offset: u32, len: u32) -> IoResult<Vec<u8>> {
-        self.file.seek(SeekFrom::Start(offset as u64))?;
-        let mut buf = Vec::with_capacity(len as usize);
-        buf.resize(len as usize, 0);
-        self.file.read_exact(&mut buf)?;
-        Ok(buf)
+    /// Read `len` bytes at `offset`, borrowed from the memory map if the segment is mapped.
+    /// Reads don't move the file cursor, so they can run in parallel.
+    pub fn read_bytes(&self, offset: u32, len: u32) -> IoResult<Cow<'_, [u8]>> {
+        let (start, end) = (offset as usize, offset as usize + len as usize);
+        match self.mmap {
+            Some(ref mmap) if mmap.len() < end => Err(IoError::from(ErrorKind::UnexpectedEof)),
+            Some(ref mmap) => Ok(Cow::Borrowed(&mmap[start..end])),
+            None => {
+                let mut buf = vec![0; len as usize];
+                self.file.read_exact_at(&mut buf, u64::from(offset))?;
+                Ok(Cow::Owned(buf))
+            }
+        }
+    }
+
+    // Drop everything after `offset`, only allowed on the writable segment.
+    pub fn truncate(&self, offset: u32) -> IoResult<()> {
+        assert!(!self.readonly);
+        self.file.set_len(u64::from(offset))?;
+        self.file.sync_all()?;
+        self.write_offset.store(offset, Ordering::Release);
+        Ok(())
     }
 
-    pub fn write_bytes(&mut self, buf: &[u8], sync: bool) -> IoResult<()> {
-        self.file.seek(SeekFrom::Start(self.write_offset as u64))?;
-        self.file.write_all(buf)?;
-        self.write_offset += buf.len() as u32;
+    // Append `buf` to the writable segment, callers serialize appends with the value log's writer lock.
+    pub fn write_bytes(&self, buf: &[u8], sync: bool) -> IoResult<()> {
+        assert!(!self.readonly);
+        let offset = self.write_offset.load(Ordering::Acquire);
+        self.file.write_all_at(buf, u64::from(offset))?;
 
         if sync {
-            self.file.flush()?;
             self.file.sync_data()?;
         }
+        self.write_offset
+            .store(offset + buf.len() as u32, Ordering::Release);
         Ok(())
     }
 }
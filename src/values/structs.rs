@@ -1,37 +1,79 @@
+// the impls generated by `derive(Fail)` are non-local.
+#![allow(non_local_definitions)]
+
 extern crate crc;
 use self::crc::{Hasher32, crc32};
-use std::io::Result as IoResult;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use failure::Error;
+
+/// The checksum stored in an entry doesn't match the one computed from its content.
+#[derive(Debug, Fail)]
+#[fail(display = "Checksum mismatch: expected({:#010x}) actual({:#010x})", expected, actual)]
+pub struct ChecksumMismatch {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+// Bits of `meta` in value log entries and table entries.
+/// The entry is a tombstone of the key.
+pub const BIT_DELETE: u8 = 1;
+/// The entry is written in a transaction.
+pub const BIT_TXN: u8 = 1 << 1;
+/// The entry marks the end of a transaction, all entries of it are before the marker.
+pub const BIT_FIN_TXN: u8 = 1 << 2;
+
 #[derive(Clone, Eq, PartialEq, Debug)]
 pub struct Value {
     pub key: Vec<u8>,
     pub value: Vec<u8>,
+    pub meta: u8,
 }
 impl Value {
     pub fn new(key: &[u8], value: &[u8]) -> Value {
         Value {
             key: key.to_vec(),
             value: value.to_vec(),
+            meta: 0,
+        }
+    }
+
+    /// Create a delete marker of `key`.
+    pub fn tombstone(key: &[u8]) -> Value {
+        Value {
+            key: key.to_vec(),
+            value: vec![],
+            meta: BIT_DELETE,
         }
     }
+
+    #[inline]
+    pub fn is_deleted(&self) -> bool {
+        self.meta & BIT_DELETE != 0
+    }
 }
 
 struct ValueHeader {
     klen: u32,
     vlen: u32,
+    meta: u8,
 }
 
 impl ValueHeader {
+    const SIZE: u32 = 4 + 4 + 1;
+
     pub fn encode<T: WriteBytesExt>(&self, writer: &mut T) -> IoResult<u32> {
         writer.write_u32::<BigEndian>(self.klen)?;
         writer.write_u32::<BigEndian>(self.vlen)?;
-        Ok(4 + 4)
+        writer.write_u8(self.meta)?;
+        Ok(ValueHeader::SIZE)
     }
 
     pub fn decode<T: ReadBytesExt>(reader: &mut T) -> IoResult<ValueHeader> {
         let klen = reader.read_u32::<BigEndian>()?;
         let vlen = reader.read_u32::<BigEndian>()?;
-        Ok(ValueHeader { klen, vlen })
+        let meta = reader.read_u8()?;
+        Ok(ValueHeader { klen, vlen, meta })
     }
 }
 
@@ -40,29 +82,61 @@ impl Value {
         ValueHeader {
             klen: self.key.len() as u32,
             vlen: self.value.len() as u32,
+            meta: self.meta,
         }
     }
 
-    pub fn decode<T: ReadBytesExt>(reader: &mut T) -> IoResult<Value> {
+    /// Decode an entry and verify its checksum,
+    /// a mismatch is reported as `ChecksumMismatch`, other errors are `io::Error`.
+    /// An entry longer than the rest of `reader` is an `UnexpectedEof`,
+    /// it's checked before allocating, since the lengths in a corrupted header may be huge.
+    pub fn decode(reader: &mut &[u8]) -> Result<Value, Error> {
         let header = ValueHeader::decode(reader)?;
-        let mut key = Vec::with_capacity(header.klen as usize);
-        key.resize(header.klen as usize, 0);
-        let mut value = Vec::with_capacity(header.vlen as usize);
-        value.resize(header.vlen as usize, 0);
+        let len = u64::from(header.klen) + u64::from(header.vlen) + 4;
+        if len > reader.len() as u64 {
+            Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                format!("entry of {} bytes exceeds the remaining {} bytes", len, reader.len()),
+            ))?
+        }
+        let mut key = vec![0; header.klen as usize];
+        let mut value = vec![0; header.vlen as usize];
 
         reader.read_exact(&mut key)?;
         reader.read_exact(&mut value)?;
-        // TODO: check crc
-        let _crc32 = reader.read_u32::<BigEndian>()?;
+        let crc32 = reader.read_u32::<BigEndian>()?;
 
-        Ok(Value { key, value })
+        let value = Value {
+            key,
+            value,
+            meta: header.meta,
+        };
+        let actual = value.checksum();
+        if crc32 != actual {
+            Err(ChecksumMismatch {
+                expected: crc32,
+                actual,
+            })?
+        }
+        Ok(value)
+    }
+
+    fn checksum(&self) -> u32 {
+        let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+        let mut buf = Vec::with_capacity(ValueHeader::SIZE as usize);
+        // writing into a vec never fails.
+        self.get_header().encode(&mut buf).unwrap();
+        digest.write(&buf);
+        digest.write(&self.key);
+        digest.write(&self.value);
+        digest.sum32()
     }
 
     pub fn encode<T: WriteBytesExt>(&self, writer: &mut T) -> IoResult<u32> {
         let header = self.get_header();
         let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
 
-        let mut buf = Vec::with_capacity(8);
+        let mut buf = Vec::with_capacity(ValueHeader::SIZE as usize);
         let header_size = header.encode(&mut buf)?;
         writer.write_all(&buf)?;
         digest.write(&buf);
@@ -104,6 +178,10 @@ impl ValuePointer {
     pub fn len(&self) -> u32 {
         self.len
     }
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
     pub fn encode<T: WriteBytesExt>(&self, writer: &mut T) -> IoResult<u32> {
         writer.write_u32::<BigEndian>(self.fid)?;
@@ -113,7 +191,45 @@ impl ValuePointer {
         Ok(ValuePointer::SIZE)
     }
 
-    pub fn decode() {}
+    pub fn decode<T: ReadBytesExt>(reader: &mut T) -> IoResult<ValuePointer> {
+        let fid = reader.read_u32::<BigEndian>()?;
+        let len = reader.read_u32::<BigEndian>()?;
+        let offset = reader.read_u32::<BigEndian>()?;
+        Ok(ValuePointer { fid, offset, len })
+    }
+}
+
+/// `ValueStruct` is what the LSM tree stores for a key,
+/// the meta of the value log entry and where the entry is.
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct ValueStruct {
+    pub meta: u8,
+    pub pointer: ValuePointer,
+}
+
+impl ValueStruct {
+    pub const SIZE: u32 = 1 + ValuePointer::SIZE;
+
+    pub fn new(meta: u8, pointer: ValuePointer) -> ValueStruct {
+        ValueStruct { meta, pointer }
+    }
+
+    #[inline]
+    pub fn is_deleted(&self) -> bool {
+        self.meta & BIT_DELETE != 0
+    }
+
+    pub fn encode<T: WriteBytesExt>(&self, writer: &mut T) -> IoResult<u32> {
+        writer.write_u8(self.meta)?;
+        self.pointer.encode(writer)?;
+        Ok(ValueStruct::SIZE)
+    }
+
+    pub fn decode<T: ReadBytesExt>(reader: &mut T) -> IoResult<ValueStruct> {
+        let meta = reader.read_u8()?;
+        let pointer = ValuePointer::decode(reader)?;
+        Ok(ValueStruct { meta, pointer })
+    }
 }
 
 #[cfg(test)]
@@ -124,11 +240,12 @@ mod test {
         let h = ValueHeader {
             klen: 255 + 256,
             vlen: 255 + 256 + 256 * 256,
+            meta: BIT_DELETE,
         };
         let mut buf = Vec::new();
         let len = h.encode(&mut buf).unwrap();
-        assert_eq!(8, len);
-        assert_eq!(vec![0u8, 0, 1, 255, 0, 1, 1, 255], buf);
+        assert_eq!(9, len);
+        assert_eq!(vec![0u8, 0, 1, 255, 0, 1, 1, 255, 1], buf);
     }
 
     #[test]
@@ -136,13 +253,76 @@ mod test {
         let entry = Value {
             key: vec![1, 2, 3, 4],
             value: vec![5, 6, 7, 8, 9, 10],
+            meta: 0,
         };
         let mut buf = Vec::new();
         let len = entry.encode(&mut buf).unwrap();
-        assert_eq!(8 + entry.key.len() + entry.value.len() + 4, len as usize);
+        assert_eq!(9 + entry.key.len() + entry.value.len() + 4, len as usize);
         assert_eq!(
             vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10],
-            &buf[8..(buf.len() - 4)]
+            &buf[9..(buf.len() - 4)]
         );
     }
+
+    #[test]
+    pub fn test_value_struct_encode_decode() {
+        let vs = ValueStruct::new(BIT_DELETE, ValuePointer::new(1, 1024, 20));
+        let mut buf = Vec::new();
+        assert_eq!(ValueStruct::SIZE, vs.encode(&mut buf).unwrap());
+        let decoded = ValueStruct::decode(&mut &buf[..]).unwrap();
+        assert!(decoded.is_deleted());
+        assert_eq!(vs, decoded);
+    }
+
+    #[test]
+    pub fn test_decode_checksum_mismatch() {
+        let entry = Value::new(b"key", b"value");
+        let mut buf = Vec::new();
+        entry.encode(&mut buf).unwrap();
+        let last = buf.len() - 5;
+        buf[last] ^= 1;
+        let err = Value::decode(&mut &buf[..]).unwrap_err();
+        let mismatch = err.downcast_ref::<ChecksumMismatch>().unwrap();
+        assert_eq!(entry.checksum(), mismatch.expected);
+        assert_ne!(mismatch.expected, mismatch.actual);
+        // torn entry
+        let err = Value::decode(&mut &buf[..last]).unwrap_err();
+        let err = err.downcast_ref::<::std::io::Error>().unwrap();
+        assert_eq!(::std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    pub fn test_decode_corrupted_header() {
+        let entry = Value::new(b"key", b"value");
+        let mut buf = Vec::new();
+        entry.encode(&mut buf).unwrap();
+        // huge lengths fail before allocating.
+        for pos in &[0, 4] {
+            let mut buf = buf.clone();
+            buf[*pos] = 0xff;
+            let err = Value::decode(&mut &buf[..]).unwrap_err();
+            let err = err.downcast_ref::<IoError>().unwrap();
+            assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+        }
+    }
+
+    #[test]
+    pub fn test_tombstone_encode_decode() {
+        let entry = Value::tombstone(b"key");
+        assert!(entry.is_deleted());
+        let mut buf = Vec::new();
+        entry.encode(&mut buf).unwrap();
+        let decoded = Value::decode(&mut &buf[..]).unwrap();
+        assert!(decoded.is_deleted());
+        assert_eq!(entry, decoded);
+    }
+
+    #[test]
+    pub fn test_pointer_encode_decode() {
+        let p = ValuePointer::new(1, 1024, 20);
+        let mut buf = Vec::new();
+        let len = p.encode(&mut buf).unwrap();
+        assert_eq!(ValuePointer::SIZE, len);
+        assert_eq!(p, ValuePointer::decode(&mut &buf[..]).unwrap());
+    }
 }